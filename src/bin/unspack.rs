@@ -1,132 +1,246 @@
 extern crate unspacklib;
 
-use conch_parser::ast;
-use conch_parser::lexer::Lexer;
-use conch_parser::parse::DefaultParser;
 use log::*;
-use sha2::{Digest, Sha256};
-use simplelog::{Config, LevelFilter, SimpleLogger};
+use simplelog::{Config, LevelFilter, WriteLogger};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
 use std::process::exit;
 use std::{env, fs};
-use unspacklib::serializable::Serializable;
-use unspacklib::{
-    command_word,
-    transform::{ExtractCommand, FindCommandWord},
-};
-
-static COMPILE_FUNC_NAME: &str = "__unspacked_rs_compile";
-
-fn filter_parser(
-    contents: String,
-    spack_calls: &mut Vec<(String, ast::DefaultSimpleCommand)>,
-    spack_source: &mut ast::DefaultSimpleCommand,
-) -> Vec<ast::TopLevelCommand<String>> {
-    // Initialize our token lexer and shell parser with the first argument
-    let lex = Lexer::new(contents.chars());
-    let parser = DefaultParser::new(lex);
-
-    parser
-        .into_iter()
-        .filter_map(|line| {
-            if let Ok(mut ast) = line {
-                let mut export: bool = true;
-
-                if let Some(cmd) = ast.extract(".") {
-                    // Here we look for a spack setup source script
-                    if cmd.position(".*setup-env.sh").is_some() {
-                        *spack_source = cmd.clone();
-                        export = false;
-                    }
-                } else if let Some(cmd) = ast.extract("spack") {
-                    export = false;
-                    // Next we match lines beginning with spack load and no list
-                    if let Some(_) = cmd.position("load") {
-                        if let None = cmd.position("--list") {
-                            let mut spack_call = cmd.clone();
-
-                            let mut hasher = Sha256::new();
-                            hasher.update(spack_call.into_string());
-                            let result: String = format!("load_{:x}", hasher.finalize());
-
-                            spack_call.redirects_or_env_vars = vec![];
-                            spack_calls.push((String::from(&result), spack_call.clone()));
-
-                            cmd.redirects_or_cmd_words = vec![command_word!(result)];
-                            export = true;
-                        }
-                    }
-                }
+use unspacklib::database::Database;
+use unspacklib::serializable::Indent;
+use unspacklib::unspack::{Unspacked, Unspacker};
+use unspacklib::Error;
+
+static HELP: &str = "Convert the spack calls of shell scripts to plain environment modifications
+
+Options:
+  -o, --output <file>          Write the unspacked script to <file> instead of the standard output
+  -i, --in-place[=<suffix>]    Overwrite the spacked scripts, keeping a copy with <suffix> appended if given
+      --compile[=<command>]    Compile the spack calls by running spack, or the given command, directly
+      --offline <spack root>   Compile spack load calls from the install database of a spack installation
+      --shell bash|sh|csh|fish Generate the unspacked script for the given shell
+      --indent <width>|tab     Indent compound commands with <width> spaces or tabs
+      --pass-through           Copy the lines that cannot be parsed instead of failing
+      --prefix <prefix>        Start the names of the functions replacing the spack calls with <prefix>
+  -v, --verbose                Log debugging information
+  -q, --quiet                  Only log errors
+  -V, --version                Print the version and exit
+  -h, --help                   Print this message and exit
+
+A spacked script of - is read from the standard input.
+
+Without --compile or --offline, the unspacked script compiles itself where spack
+is available, and loads get no unload function undoing them.";
+
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} [options] [-o <file> | --in-place[=<suffix>]] <spacked script>...",
+        program
+    )
+}
 
-                if export {
-                    Some(ast)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
-        .collect()
+// Report a misuse of the command line and exit
+fn misuse(program: &str, message: &str) -> ! {
+    eprintln!("{}: {}\n{}", program, message, usage(program));
+    exit(1);
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    SimpleLogger::init(LevelFilter::Info, Config::default()).unwrap();
+// Where the unspacked scripts are written
+enum Output {
+    Stdout,
+    File(String),
+    // Over the input, with the suffix of a backup copy
+    InPlace(Option<String>),
+}
+
+fn read(input: &str) -> io::Result<String> {
+    let mut contents = String::new();
+
+    if input == "-" {
+        io::stdin().read_to_string(&mut contents)?;
+    } else {
+        contents = fs::read_to_string(input)?;
+    }
+
+    Ok(contents)
+}
+
+// Stream the unspacked script to its output
+fn write(input: &str, output: &Output, unspacked: &Unspacked) -> io::Result<()> {
+    let mut out: Box<dyn Write> = match output {
+        Output::Stdout => Box::new(io::stdout().lock()),
+        Output::File(path) => Box::new(BufWriter::new(File::create(path)?)),
+        Output::InPlace(backup) => {
+            if let Some(suffix) = backup {
+                fs::copy(input, format!("{}{}", input, suffix))?;
+            }
+            Box::new(BufWriter::new(File::create(input)?))
+        }
+    };
 
+    unspacked.write_to(&mut out)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+fn main() {
     let args = env::args().collect::<Vec<String>>();
+    let program = &args[0];
+
+    let mut root = None;
+    let mut spack = None;
+    let mut shell = None;
+    let mut indent = Indent::default();
+    let mut pass_through = false;
+    let mut prefix = None;
+    let mut output = Output::Stdout;
+    let mut level = LevelFilter::Info;
+    let mut inputs = vec![];
+
+    let mut arguments = args[1..].iter();
+    while let Some(argument) = arguments.next() {
+        let mut value = |option: &str| match arguments.next() {
+            Some(value) => value.clone(),
+            None => misuse(program, &format!("{} expects a value", option)),
+        };
+
+        match argument.as_str() {
+            "-o" | "--output" => output = Output::File(value(argument)),
+            "-i" | "--in-place" => output = Output::InPlace(None),
+            in_place if in_place.starts_with("--in-place=") => {
+                let suffix = &in_place["--in-place=".len()..];
+                output = Output::InPlace(Some(String::from(suffix)));
+            }
+            "--offline" => root = Some(value(argument)),
+            "--compile" => spack = Some(String::from("spack")),
+            compile if compile.starts_with("--compile=") => {
+                spack = Some(String::from(&compile["--compile=".len()..]))
+            }
+            "--shell" => shell = Some(value(argument)),
+            "--indent" => {
+                indent = match value(argument).as_str() {
+                    "tab" => Indent::Tabs,
+                    width => match width.parse() {
+                        Ok(width) => Indent::Spaces(width),
+                        Err(_) => misuse(program, &format!("Invalid indentation {}", width)),
+                    },
+                }
+            }
+            "--pass-through" => pass_through = true,
+            "--prefix" => prefix = Some(value(argument)),
+            "-v" | "--verbose" => level = LevelFilter::Debug,
+            "-q" | "--quiet" => level = LevelFilter::Error,
+            "-V" | "--version" => {
+                println!("unspack {}", env!("CARGO_PKG_VERSION"));
+                exit(0);
+            }
+            "-h" | "--help" => {
+                println!("{}\n\n{}", usage(program), HELP);
+                exit(0);
+            }
+            option if option.starts_with('-') && option != "-" => {
+                misuse(program, &format!("Unknown option {}", option))
+            }
+            _ => inputs.push(argument.as_str()),
+        }
+    }
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <spacked script>", &args[0]);
-        exit(1);
+    if inputs.is_empty() {
+        misuse(program, "No spacked script given");
     }
 
-    let filename = &args[1];
+    match output {
+        Output::File(_) if inputs.len() > 1 => {
+            misuse(program, "--output expects a single spacked script")
+        }
+        Output::InPlace(_) if inputs.contains(&"-") => {
+            misuse(program, "--in-place cannot overwrite the standard input")
+        }
+        _ => (),
+    }
 
-    debug!("Reading file: {}", filename);
-    let contents = fs::read_to_string(filename).expect("Error reading file");
+    if root.is_some() && spack.is_some() {
+        misuse(program, "--compile and --offline cannot be used together");
+    }
 
-    // This will contain the spack setup source call
-    let mut spack_source: ast::DefaultSimpleCommand = ast::DefaultSimpleCommand {
-        redirects_or_env_vars: vec![],
-        redirects_or_cmd_words: vec![],
-    };
-    // This will hold information about the spack calls found in the script
-    let mut spack_calls = Vec::new();
-
-    // Parse our input!
-    let transformed = filter_parser(contents, &mut spack_calls, &mut spack_source);
-
-    let compile_directives = spack_calls
-        .iter_mut()
-        .filter_map(|tuple| {
-            if let Some(index) = tuple.1.position("load") {
-                tuple
-                    .1
-                    .redirects_or_cmd_words
-                    .insert(index + 1, command_word!("--sh"));
-                Some(format!(
-                    "HASH={} {} {}",
-                    tuple.0,
-                    COMPILE_FUNC_NAME,
-                    tuple.1.into_string()
-                ))
-            } else {
-                None
+    // Logs go to stderr, keeping the standard output for the script
+    WriteLogger::init(level, Config::default(), io::stderr()).unwrap();
+
+    let mut unspacker = Unspacker::new().indent(indent).pass_through(pass_through);
+
+    if let Some(prefix) = prefix {
+        unspacker = unspacker
+            .prefix(&prefix)
+            .unwrap_or_else(|e| misuse(program, &e.to_string()));
+    }
+
+    if let Some(name) = shell {
+        match name.parse() {
+            Ok(shell) => unspacker = unspacker.shell(shell),
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(root) = root {
+        debug!("Reading spack database from: {}", root);
+        match Database::open(Path::new(&root)) {
+            Ok(database) => unspacker = unspacker.offline(database),
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(command) = spack {
+        unspacker = unspacker.compile(&command.split_whitespace().collect::<Vec<&str>>());
+    }
+
+    // Every script is processed, even if some fail
+    let mut failed = false;
+
+    for input in inputs {
+        let name = if input == "-" { "<stdin>" } else { input };
+
+        debug!("Reading file: {}", name);
+        let contents = match read(input) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("{}: {}", name, e);
+                failed = true;
+                continue;
+            }
+        };
+
+        let unspacked = match unspacker.unspack(&contents) {
+            Ok(unspacked) => unspacked,
+            Err(Error::Parse(unparsed)) => {
+                error!("{}:{}", name, unparsed);
+                failed = true;
+                continue;
+            }
+            Err(e) => {
+                error!("{}: {}", name, e);
+                failed = true;
+                continue;
             }
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    println!(
-        include_str!("template.sh.fmt"),
-        spack_source.into_string(),
-        COMPILE_FUNC_NAME,
-        transformed
-            .iter()
-            .map(|ast| ast.into_string())
-            .collect::<Vec<String>>()
-            .join("\n"),
-        compile_directives
-    );
-
-    Ok(())
+        };
+
+        for unparsed in &unspacked.unparsed {
+            warn!("{}:{}", name, unparsed);
+        }
+
+        if let Err(e) = write(input, &output, &unspacked) {
+            error!("{}: {}", name, e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
 }
@@ -0,0 +1,455 @@
+use crate::spec::{self, Spec, ARCH_PARAMETERS};
+use crate::Error;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Location of the install database, relative to the root of a spack installation
+static INDEX_PATH: &str = "opt/spack/.spack-db/index.json";
+
+// Spack's default prefix inspections: subdirectories of an install prefix and the
+// variable they are prepended to when they exist
+pub static PREFIX_INSPECTIONS: [(&str, &str); 10] = [
+    ("bin", "PATH"),
+    ("man", "MANPATH"),
+    ("share/man", "MANPATH"),
+    ("share/aclocal", "ACLOCAL_PATH"),
+    ("lib", "LD_LIBRARY_PATH"),
+    ("lib64", "LD_LIBRARY_PATH"),
+    ("lib/pkgconfig", "PKG_CONFIG_PATH"),
+    ("lib64/pkgconfig", "PKG_CONFIG_PATH"),
+    ("share/pkgconfig", "PKG_CONFIG_PATH"),
+    ("", "CMAKE_PREFIX_PATH"),
+];
+
+// The dependency types spack load follows
+static LOADED_DEPTYPES: [&str; 2] = ["link", "run"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub hash: String,
+    pub deptypes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Install {
+    pub hash: String,
+    pub name: String,
+    pub version: String,
+    pub compiler: Option<(String, String)>,
+    // Architecture parameters, as in platform=linux os=ubuntu20.04 target=x86_64
+    pub arch: BTreeMap<String, String>,
+    // Variants and compiler flags, formatted as spec parameters
+    pub parameters: BTreeMap<String, Vec<String>>,
+    pub prefix: PathBuf,
+    pub dependencies: Vec<Dependency>,
+}
+
+impl Install {
+    // Hashes of the dependencies loaded along with this install
+    pub fn loaded_dependencies(&self) -> impl Iterator<Item = &str> {
+        self.dependencies
+            .iter()
+            .filter(|d| {
+                d.deptypes
+                    .iter()
+                    .any(|t| LOADED_DEPTYPES.contains(&t.as_str()))
+            })
+            .map(|d| d.hash.as_str())
+    }
+
+    fn satisfies_parameter(&self, key: &str, values: &[String]) -> bool {
+        if ARCH_PARAMETERS.contains(&key) {
+            let arch = match key {
+                "arch" => ["platform", "os", "target"]
+                    .iter()
+                    .filter_map(|k| self.arch.get(*k))
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join("-"),
+                _ => self.arch.get(key).cloned().unwrap_or_default(),
+            };
+
+            return values.join(",") == arch;
+        }
+
+        match self.parameters.get(key) {
+            Some(installed) => values.iter().all(|v| installed.contains(v)),
+            None => false,
+        }
+    }
+
+    // Return true if the install satisfies all the constraints of the spec, save
+    // for those on dependencies
+    fn satisfies_node(&self, spec: &Spec) -> bool {
+        let name = spec.name.as_ref().is_none_or(|name| {
+            // Namespaced names such as builtin.zlib match on the last component
+            name == &self.name || name.ends_with(&format!(".{}", self.name))
+        });
+
+        let versions = spec
+            .versions
+            .as_ref()
+            .is_none_or(|v| spec::version_satisfies(&self.version, v));
+
+        let compiler = match (&spec.compiler, &self.compiler) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(required), Some((name, version))) => {
+                &required.name == name
+                    && required
+                        .versions
+                        .as_ref()
+                        .is_none_or(|v| spec::version_satisfies(version, v))
+            }
+        };
+
+        let hash = spec
+            .hash
+            .as_ref()
+            .is_none_or(|h| self.hash.starts_with(h.as_str()));
+
+        name && versions
+            && compiler
+            && hash
+            && spec
+                .parameters
+                .iter()
+                .all(|(key, values)| self.satisfies_parameter(key, values))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Database {
+    pub installs: Vec<Install>,
+}
+
+// Extract the dependencies of a spec, for all the database layouts spack has used
+fn dependencies(spec: &Value) -> Vec<Dependency> {
+    let entries: Vec<&Value> = match spec.get("dependencies") {
+        Some(Value::Array(deps)) => deps.iter().collect(),
+        Some(Value::Object(deps)) => deps.values().collect(),
+        _ => vec![],
+    };
+
+    entries
+        .into_iter()
+        .filter_map(|dep| {
+            let deptypes = dep
+                .get("type")
+                .or_else(|| dep.pointer("/parameters/deptypes"))
+                .and_then(Value::as_array)
+                .map(|types| {
+                    types
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            Some(Dependency {
+                hash: dep.get("hash")?.as_str()?.to_string(),
+                deptypes,
+            })
+        })
+        .collect()
+}
+
+// Format the variants and flags of a spec as spec parameters
+fn parameters(spec: &Value) -> BTreeMap<String, Vec<String>> {
+    let mut parameters = BTreeMap::new();
+
+    for (key, value) in spec
+        .get("parameters")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        let values = match value {
+            Value::Bool(b) => vec![b.to_string()],
+            Value::String(s) => vec![s.clone()],
+            // Compiler flags are stored as lists of single flags
+            Value::Array(a) if key.ends_with("flags") || key == "ldlibs" => {
+                let flags = a.iter().filter_map(Value::as_str).collect::<Vec<&str>>();
+                vec![flags.join(" ")]
+            }
+            Value::Array(a) => a
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            _ => continue,
+        };
+
+        parameters.insert(key.clone(), values);
+    }
+
+    parameters
+}
+
+fn arch(spec: &Value) -> BTreeMap<String, String> {
+    let mut arch = BTreeMap::new();
+
+    for (key, pointer) in [
+        ("platform", "/arch/platform"),
+        ("os", "/arch/platform_os"),
+        ("target", "/arch/target"),
+    ] {
+        // Targets are either a name or a description of the microarchitecture
+        let value = spec.pointer(pointer).and_then(|v| match v {
+            Value::String(s) => Some(s.as_str()),
+            v => v.get("name").and_then(Value::as_str),
+        });
+
+        if let Some(value) = value {
+            arch.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    arch
+}
+
+impl Database {
+    // Read the install database of the spack installation at the given root
+    pub fn open(root: &Path) -> Result<Database, Error> {
+        let index = root.join(INDEX_PATH);
+        let contents = fs::read_to_string(&index)
+            .map_err(|e| Error::Database(format!("Error reading {}: {}", index.display(), e)))?;
+
+        Database::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Database, Error> {
+        let index: Value = serde_json::from_str(contents)
+            .map_err(|e| Error::Database(format!("Malformed database: {}", e)))?;
+
+        let installs = index
+            .pointer("/database/installs")
+            .and_then(Value::as_object)
+            .ok_or_else(|| {
+                Error::Database(String::from("No installs found in the spack database"))
+            })?;
+
+        let mut database = Database::default();
+
+        for (hash, record) in installs {
+            if !record
+                .get("installed")
+                .and_then(Value::as_bool)
+                .unwrap_or(true)
+            {
+                continue;
+            }
+
+            // Older databases key the spec by its name
+            let (name, spec) = match record.get("spec") {
+                Some(spec) if spec.get("name").is_some() => {
+                    (spec["name"].as_str().unwrap_or_default().to_string(), spec)
+                }
+                Some(Value::Object(spec)) if spec.len() == 1 => {
+                    let (name, spec) = spec.iter().next().unwrap();
+                    (name.clone(), spec)
+                }
+                _ => {
+                    return Err(Error::Database(format!(
+                        "Malformed database record for {}",
+                        hash
+                    )))
+                }
+            };
+
+            database.installs.push(Install {
+                hash: hash.clone(),
+                name,
+                version: spec
+                    .get("version")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                compiler: spec.get("compiler").and_then(|c| {
+                    Some((
+                        c.get("name")?.as_str()?.to_string(),
+                        c.get("version")?.as_str()?.to_string(),
+                    ))
+                }),
+                arch: arch(spec),
+                parameters: parameters(spec),
+                prefix: PathBuf::from(
+                    record
+                        .get("path")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default(),
+                ),
+                dependencies: dependencies(spec),
+            });
+        }
+
+        Ok(database)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Install> {
+        self.installs.iter().find(|i| i.hash == hash)
+    }
+
+    // Return all the dependencies of an install, of any type and at any depth
+    fn dependency_closure<'a>(&'a self, install: &'a Install) -> Vec<&'a Install> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![install];
+        let mut closure = vec![];
+
+        while let Some(node) = stack.pop() {
+            for dep in node.dependencies.iter().filter_map(|d| self.get(&d.hash)) {
+                if seen.insert(dep.hash.as_str()) {
+                    closure.push(dep);
+                    stack.push(dep);
+                }
+            }
+        }
+
+        closure
+    }
+
+    // Return true if an install satisfies a spec, including its dependencies
+    pub fn satisfies(&self, install: &Install, spec: &Spec) -> bool {
+        if !install.satisfies_node(spec) {
+            return false;
+        }
+
+        if spec.dependencies.is_empty() {
+            return true;
+        }
+
+        let closure = self.dependency_closure(install);
+        spec.dependencies
+            .iter()
+            .all(|dep| closure.iter().any(|node| self.satisfies(node, dep)))
+    }
+
+    pub fn find(&self, spec: &Spec) -> Vec<&Install> {
+        self.installs
+            .iter()
+            .filter(|i| self.satisfies(i, spec))
+            .collect()
+    }
+
+    // Return the given installs and their loaded dependencies, dependencies first
+    pub fn closure<'a>(&'a self, roots: &[&'a Install]) -> Vec<&'a Install> {
+        fn visit<'a>(
+            database: &'a Database,
+            install: &'a Install,
+            seen: &mut HashSet<&'a str>,
+            order: &mut Vec<&'a Install>,
+        ) {
+            if !seen.insert(&install.hash) {
+                return;
+            }
+
+            for dep in install
+                .loaded_dependencies()
+                .filter_map(|h| database.get(h))
+            {
+                visit(database, dep, seen, order);
+            }
+
+            order.push(install);
+        }
+
+        let mut seen = HashSet::new();
+        let mut order = vec![];
+
+        for root in roots {
+            visit(self, root, &mut seen, &mut order);
+        }
+
+        order
+    }
+}
+
+// Compute the path entries prepended to each variable when loading the given
+// installs in order, following the prefix inspections
+pub fn environment(installs: &[&Install]) -> BTreeMap<String, Vec<String>> {
+    let mut variables: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for install in installs {
+        for (subdirectory, variable) in PREFIX_INSPECTIONS.iter() {
+            let path = install.prefix.join(subdirectory);
+
+            if path.is_dir() {
+                let entries = variables.entry(variable.to_string()).or_default();
+                let path = path.to_string_lossy().trim_end_matches('/').to_string();
+
+                // Entries are prepended, so the last loaded comes first
+                entries.retain(|e| e != &path);
+                entries.insert(0, path);
+            }
+        }
+
+        variables
+            .entry(String::from("SPACK_LOADED_HASHES"))
+            .or_default()
+            .insert(0, install.hash.clone());
+    }
+
+    variables
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static INDEX: &str = r#"{"database": {"version": "7", "installs": {
+        "aaaa": {"spec": {"name": "zlib", "version": "1.2.13", "hash": "aaaa",
+                          "parameters": {"optimize": true, "shared": false, "cflags": ["-O3", "-g"]}},
+                 "path": "/opt/zlib", "installed": true, "explicit": false},
+        "bbbb": {"spec": {"name": "cmake", "version": "3.24.2", "hash": "bbbb",
+                          "compiler": {"name": "gcc", "version": "9.3.0"},
+                          "arch": {"platform": "linux", "platform_os": "ubuntu20.04",
+                                   "target": {"name": "x86_64"}},
+                          "dependencies": [{"name": "zlib", "hash": "aaaa",
+                                            "parameters": {"deptypes": ["build", "link"]}},
+                                           {"name": "ncurses", "hash": "cccc",
+                                            "parameters": {"deptypes": ["build"]}}]},
+                 "path": "/opt/cmake", "installed": true, "explicit": true},
+        "cccc": {"spec": {"name": "ncurses", "version": "6.3", "hash": "cccc"},
+                 "path": "/opt/ncurses", "installed": true, "explicit": false}
+    }}}"#;
+
+    fn find<'a>(database: &'a Database, spec: &str) -> Vec<&'a Install> {
+        let words = spec.split(' ').collect::<Vec<&str>>();
+        database.find(&spec::parse(&words).unwrap()[0])
+    }
+
+    #[test]
+    fn test_database_find() {
+        let database = Database::parse(INDEX).unwrap();
+
+        assert_eq!(find(&database, "cmake").len(), 1);
+        assert_eq!(find(&database, "cmake@3.24").len(), 1);
+        assert_eq!(find(&database, "cmake@3.2").len(), 0);
+        assert_eq!(find(&database, "cmake%gcc@9").len(), 1);
+        assert_eq!(
+            find(&database, "cmake arch=linux-ubuntu20.04-x86_64").len(),
+            1
+        );
+        assert_eq!(find(&database, "cmake ^ncurses@6").len(), 1);
+        assert_eq!(find(&database, "cmake ^zlib~optimize").len(), 0);
+        assert_eq!(find(&database, "zlib+optimize~shared").len(), 1);
+        assert_eq!(find(&database, "/cc")[0].name, "ncurses");
+    }
+
+    #[test]
+    fn test_database_closure() {
+        let database = Database::parse(INDEX).unwrap();
+        let cmake = find(&database, "cmake");
+
+        // Build dependencies are not loaded
+        let names = database
+            .closure(&cmake)
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["zlib", "cmake"]);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Environment variables, by name
+pub type Environment = BTreeMap<String, String>;
+
+// A change made to an environment variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Modification {
+    Set(String),
+    Unset,
+    // Entries added to the front of a colon-separated variable
+    Prepend(Vec<String>),
+    // Entries added to the back of a colon-separated variable
+    Append(Vec<String>),
+    // Entries removed from a colon-separated variable
+    Remove(Vec<String>),
+    // Entries added to the back of a colon-separated set, where they appear once
+    Add(Vec<String>),
+}
+
+// Variables holding a colon-separated set, whose order does not matter
+pub static SETS: [&str; 1] = ["SPACK_LOADED_HASHES"];
+
+// Return true if a variable holds a colon-separated list of paths
+pub fn is_path_list(name: &str) -> bool {
+    name.ends_with("PATH")
+}
+
+fn entries(value: &str) -> Vec<String> {
+    value
+        .split(':')
+        .filter(|e| !e.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// A run of new entries found in the same order in an old value
+struct Run<'a> {
+    start: usize,
+    // The old position of its last entry
+    last: usize,
+    // The occurrences of each entry it holds
+    counts: HashMap<&'a str, usize>,
+    // The number of entries with occurrences missing
+    missing: usize,
+    // The start of its part holding no entry repeated in the old value, which can
+    // be kept even if the run misses occurrences
+    single: usize,
+}
+
+// Express the new value of a colon-separated variable as entries removed from its
+// old value, then entries added around the rest; the rest is chosen as large as
+// found in a single pass, so that most of the old value is kept. Returns None if
+// none of it is
+fn list_diff(old: &[String], new: &[String]) -> Option<Vec<Modification>> {
+    if old.is_empty() {
+        return Some(vec![Modification::Prepend(new.to_vec())]);
+    }
+
+    // The positions of each entry in the old value
+    let mut positions: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, entry) in old.iter().enumerate() {
+        positions.entry(entry).or_default().push(index);
+    }
+
+    // The rest is a run of new entries found in the same order in the old value,
+    // holding every occurrence of its entries there, as removing the others would
+    // remove them as well; runs are followed in a single pass
+    let mut best: Option<(usize, usize)> = None;
+    let mut run: Option<Run> = None;
+
+    for (index, entry) in new.iter().enumerate() {
+        let occurrences = match positions.get(entry.as_str()) {
+            Some(occurrences) => occurrences,
+            None => {
+                run = None;
+                continue;
+            }
+        };
+
+        let extends = run.as_ref().is_some_and(|run| {
+            let count = run.counts.get(entry.as_str()).copied().unwrap_or_default();
+            occurrences
+                .get(count)
+                .is_some_and(|position| *position > run.last)
+        });
+        let run = match run.as_mut() {
+            Some(run) if extends => run,
+            _ => run.insert(Run {
+                start: index,
+                last: 0,
+                counts: HashMap::new(),
+                missing: 0,
+                single: index,
+            }),
+        };
+
+        let count = run.counts.entry(entry).or_default();
+        run.last = occurrences[*count];
+        *count += 1;
+
+        if *count == 1 {
+            run.missing += 1;
+        }
+        if *count == occurrences.len() {
+            run.missing -= 1;
+        }
+        if occurrences.len() > 1 {
+            run.single = index + 1;
+        }
+
+        let start = if run.missing == 0 {
+            run.start
+        } else {
+            run.single
+        };
+        if start <= index && best.is_none_or(|(s, e)| e - s < index + 1 - start) {
+            best = Some((start, index + 1));
+        }
+    }
+
+    let (start, end) = best?;
+    let kept = new[start..end]
+        .iter()
+        .map(String::as_str)
+        .collect::<HashSet<&str>>();
+
+    Some(
+        [
+            Modification::Remove(
+                old.iter()
+                    .filter(|e| !kept.contains(e.as_str()))
+                    .cloned()
+                    .collect(),
+            ),
+            Modification::Prepend(new[..start].to_vec()),
+            Modification::Append(new[end..].to_vec()),
+        ]
+        .into_iter()
+        .filter(|modification| match modification {
+            Modification::Remove(e) | Modification::Prepend(e) | Modification::Append(e) => {
+                !e.is_empty()
+            }
+            _ => true,
+        })
+        .collect(),
+    )
+}
+
+// Return the modifications turning an environment into another; colon-separated
+// variables are modified relative to their current value, so that the changes
+// compose with the environment they are applied to
+pub fn diff(before: &Environment, after: &Environment) -> Vec<(String, Modification)> {
+    let mut modifications = vec![];
+
+    for name in before.keys().filter(|name| !after.contains_key(*name)) {
+        modifications.push((name.clone(), Modification::Unset));
+    }
+
+    for (name, value) in after {
+        let changes = match before.get(name) {
+            Some(old) if old == value => vec![],
+            old if SETS.contains(&name.as_str()) => {
+                let old = entries(old.map(String::as_str).unwrap_or_default());
+                let new = entries(value);
+
+                [
+                    Modification::Remove(old.iter().filter(|e| !new.contains(e)).cloned().collect()),
+                    Modification::Add(new.iter().filter(|e| !old.contains(e)).cloned().collect()),
+                ]
+                .into_iter()
+                .filter(|m| !matches!(m, Modification::Remove(e) | Modification::Add(e) if e.is_empty()))
+                .collect()
+            }
+            None if is_path_list(name) => vec![Modification::Prepend(entries(value))],
+            Some(old) if is_path_list(name) => list_diff(&entries(old), &entries(value))
+                .unwrap_or_else(|| vec![Modification::Set(value.clone())]),
+            _ => vec![Modification::Set(value.clone())],
+        };
+
+        modifications.extend(changes.into_iter().map(|m| (name.clone(), m)));
+    }
+
+    modifications.sort_by(|a, b| a.0.cmp(&b.0));
+    modifications
+}
+
+// Return the modifications undoing the given ones, made to an environment holding
+// the given variables; entries moved within a variable are left in place
+pub fn revert(
+    before: &Environment,
+    modifications: &[(String, Modification)],
+) -> Vec<(String, Modification)> {
+    let entries_of = |variable: &str, removed: bool| {
+        modifications
+            .iter()
+            .filter(|(name, _)| name == variable)
+            .flat_map(|(_, modification)| match modification {
+                Modification::Remove(entries) if removed => entries.clone(),
+                Modification::Prepend(entries)
+                | Modification::Append(entries)
+                | Modification::Add(entries)
+                    if !removed =>
+                {
+                    entries.clone()
+                }
+                _ => vec![],
+            })
+            .collect::<Vec<String>>()
+    };
+
+    modifications
+        .iter()
+        .rev()
+        .filter_map(|(name, modification)| {
+            let reverted = match modification {
+                Modification::Set(_) | Modification::Unset => match before.get(name) {
+                    Some(value) => Modification::Set(value.clone()),
+                    None => Modification::Unset,
+                },
+                Modification::Prepend(entries)
+                | Modification::Append(entries)
+                | Modification::Add(entries) => {
+                    let moved = entries_of(name, true);
+                    Modification::Remove(
+                        entries
+                            .iter()
+                            .filter(|e| !moved.contains(e))
+                            .cloned()
+                            .collect(),
+                    )
+                }
+                Modification::Remove(entries) => {
+                    let moved = entries_of(name, false);
+                    let entries = entries
+                        .iter()
+                        .filter(|e| !moved.contains(e))
+                        .cloned()
+                        .collect();
+
+                    if SETS.contains(&name.as_str()) {
+                        Modification::Add(entries)
+                    } else {
+                        Modification::Append(entries)
+                    }
+                }
+            };
+
+            match &reverted {
+                Modification::Remove(e) | Modification::Append(e) | Modification::Add(e)
+                    if e.is_empty() =>
+                {
+                    None
+                }
+                _ => Some((name.clone(), reverted)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn environment(variables: &[(&str, &str)]) -> Environment {
+        variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn list(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = environment(&[
+            ("PATH", "/usr/bin:/bin"),
+            ("MANPATH", "/usr/share/man"),
+            ("CC", "gcc"),
+            ("OLD", "x"),
+        ]);
+        let after = environment(&[
+            ("PATH", "/opt/a/bin:/usr/bin:/bin:/opt/z/bin"),
+            ("MANPATH", "/opt/a/man"),
+            ("CC", "clang"),
+            ("CMAKE_PREFIX_PATH", "/opt/a"),
+            ("SPACK_ENV", "/envs/a"),
+        ]);
+
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("CC".into(), Modification::Set("clang".into())),
+                (
+                    "CMAKE_PREFIX_PATH".into(),
+                    Modification::Prepend(list(&["/opt/a"]))
+                ),
+                ("MANPATH".into(), Modification::Set("/opt/a/man".into())),
+                ("OLD".into(), Modification::Unset),
+                ("PATH".into(), Modification::Prepend(list(&["/opt/a/bin"]))),
+                ("PATH".into(), Modification::Append(list(&["/opt/z/bin"]))),
+                ("SPACK_ENV".into(), Modification::Set("/envs/a".into())),
+            ]
+        );
+        assert!(diff(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn test_diff_moved() {
+        // Spack moves entries already present to the front
+        let before = environment(&[("PATH", "/usr/bin:/opt/a/bin:/bin")]);
+        let after = environment(&[("PATH", "/opt/a/bin:/usr/bin:/bin")]);
+
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("PATH".into(), Modification::Remove(list(&["/opt/a/bin"]))),
+                ("PATH".into(), Modification::Prepend(list(&["/opt/a/bin"]))),
+            ]
+        );
+
+        // Moved entries stay where they are once reverted
+        assert!(revert(&before, &diff(&before, &after)).is_empty());
+
+        let unloaded = environment(&[("PATH", "/usr/bin:/bin")]);
+        assert_eq!(
+            diff(&before, &unloaded),
+            vec![("PATH".into(), Modification::Remove(list(&["/opt/a/bin"])))]
+        );
+    }
+
+    #[test]
+    fn test_diff_long() {
+        let old = (0..4000).map(|i| format!("/old/{}", i)).collect::<Vec<_>>();
+        let added = (0..2000).map(|i| format!("/new/{}", i)).collect::<Vec<_>>();
+
+        // The old value is kept whole, however long
+        let before = environment(&[("LD_LIBRARY_PATH", &old.join(":"))]);
+        let after = environment(&[(
+            "LD_LIBRARY_PATH",
+            &[added.clone(), old.clone()].concat().join(":"),
+        )]);
+        assert_eq!(
+            diff(&before, &after),
+            vec![("LD_LIBRARY_PATH".into(), Modification::Prepend(added))]
+        );
+
+        // Entries appearing several times are kept with all their occurrences
+        let before = environment(&[("PATH", "/a:/b:/a:/c:/d")]);
+        let after = environment(&[("PATH", "/x:/a:/b:/a:/c:/d:/y")]);
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("PATH".into(), Modification::Prepend(list(&["/x"]))),
+                ("PATH".into(), Modification::Append(list(&["/y"]))),
+            ]
+        );
+
+        let after = environment(&[("PATH", "/x:/a:/b:/c:/d")]);
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("PATH".into(), Modification::Remove(list(&["/a", "/a"]))),
+                ("PATH".into(), Modification::Prepend(list(&["/x", "/a"]))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_revert() {
+        let before = environment(&[("PATH", "/usr/bin:/bin"), ("CC", "gcc")]);
+        let after = environment(&[
+            ("PATH", "/opt/a/bin:/usr/bin"),
+            ("CC", "clang"),
+            ("SPACK_ENV", "/envs/a"),
+        ]);
+
+        assert_eq!(
+            revert(&before, &diff(&before, &after)),
+            vec![
+                ("SPACK_ENV".into(), Modification::Unset),
+                ("PATH".into(), Modification::Remove(list(&["/opt/a/bin"]))),
+                ("PATH".into(), Modification::Append(list(&["/bin"]))),
+                ("CC".into(), Modification::Set("gcc".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_set() {
+        // Hashes are added to the set without taking its value at compile time
+        let before = environment(&[("SPACK_LOADED_HASHES", "aaaa:bbbb")]);
+        let after = environment(&[("SPACK_LOADED_HASHES", "cccc:aaaa:bbbb")]);
+        let modifications = diff(&before, &after);
+
+        assert_eq!(
+            modifications,
+            vec![(
+                "SPACK_LOADED_HASHES".into(),
+                Modification::Add(list(&["cccc"]))
+            )]
+        );
+        assert_eq!(
+            revert(&before, &modifications),
+            vec![(
+                "SPACK_LOADED_HASHES".into(),
+                Modification::Remove(list(&["cccc"]))
+            )]
+        );
+        assert_eq!(
+            diff(&Environment::new(), &after),
+            vec![(
+                "SPACK_LOADED_HASHES".into(),
+                Modification::Add(list(&["cccc", "aaaa", "bbbb"]))
+            )]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use crate::parse::Unparsed;
+use std::{fmt, io};
+
+// The errors returned by the library
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Shell code the parser rejected
+    Parse(Unparsed),
+    // A spec given to a spack call that could not be understood
+    Spec(String),
+    // A construct with no equivalent in the target shell, or that cannot be compiled
+    Unsupported(String),
+    Regex(regex::Error),
+    // A spack install database that could not be read, or that misses a package
+    Database(String),
+    // A spack call that failed to run
+    Spack(String),
+}
+
+impl Error {
+    // Prefix the message of an error with what it relates to
+    pub(crate) fn context(self, what: &str) -> Error {
+        match self {
+            Error::Spec(message) => Error::Spec(format!("{}: {}", what, message)),
+            Error::Unsupported(message) => Error::Unsupported(format!("{}: {}", what, message)),
+            Error::Database(message) => Error::Database(format!("{}: {}", what, message)),
+            Error::Spack(message) => Error::Spack(format!("{}: {}", what, message)),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse(unparsed) => write!(f, "{}", unparsed),
+            Error::Regex(e) => write!(f, "{}", e),
+            Error::Spec(message)
+            | Error::Unsupported(message)
+            | Error::Database(message)
+            | Error::Spack(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse(unparsed) => Some(unparsed),
+            Error::Regex(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Unparsed> for Error {
+    fn from(unparsed: Unparsed) -> Self {
+        Error::Parse(unparsed)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Regex(e)
+    }
+}
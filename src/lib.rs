@@ -1,2 +1,11 @@
+pub mod database;
+pub mod environment;
+mod error;
+pub mod parse;
 pub mod serializable;
+pub mod shell;
+pub mod spec;
 pub mod transform;
+pub mod unspack;
+
+pub use error::Error;
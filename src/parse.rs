@@ -0,0 +1,464 @@
+use conch_parser::ast;
+use conch_parser::ast::builder::{
+    Builder, CaseFragments, CommandGroup, ComplexWordKind, DefaultBuilder, ForFragments,
+    GuardBodyPairGroup, IfFragments, LoopKind, Newline, RedirectKind, SeparatorKind,
+};
+use conch_parser::lexer::Lexer;
+use conch_parser::parse::{ParseError, Parser, SourcePos};
+use conch_parser::token::Token;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::str::Chars;
+
+type Inner = DefaultBuilder<String>;
+
+// The comments surrounding the last command built
+#[derive(Default)]
+struct Layout {
+    leading: Vec<Newline>,
+    trailing: Option<Newline>,
+}
+
+// The default builder drops comments and blank lines; this one records those of
+// the last command completed, which is the top-level one once it is returned
+#[derive(Default)]
+struct LayoutBuilder {
+    inner: Inner,
+    layout: Rc<RefCell<Layout>>,
+}
+
+impl Builder for LayoutBuilder {
+    type Command = <Inner as Builder>::Command;
+    type CommandList = <Inner as Builder>::CommandList;
+    type ListableCommand = <Inner as Builder>::ListableCommand;
+    type PipeableCommand = <Inner as Builder>::PipeableCommand;
+    type CompoundCommand = <Inner as Builder>::CompoundCommand;
+    type Word = <Inner as Builder>::Word;
+    type Redirect = <Inner as Builder>::Redirect;
+    type Error = <Inner as Builder>::Error;
+
+    fn complete_command(
+        &mut self,
+        pre_cmd_comments: Vec<Newline>,
+        list: Self::CommandList,
+        separator: SeparatorKind,
+        cmd_comment: Option<Newline>,
+    ) -> Result<Self::Command, Self::Error> {
+        *self.layout.borrow_mut() = Layout {
+            leading: pre_cmd_comments.clone(),
+            trailing: cmd_comment.clone(),
+        };
+
+        self.inner
+            .complete_command(pre_cmd_comments, list, separator, cmd_comment)
+    }
+
+    fn and_or_list(
+        &mut self,
+        first: Self::ListableCommand,
+        rest: Vec<(Vec<Newline>, ast::AndOr<Self::ListableCommand>)>,
+    ) -> Result<Self::CommandList, Self::Error> {
+        self.inner.and_or_list(first, rest)
+    }
+
+    fn pipeline(
+        &mut self,
+        bang: bool,
+        cmds: Vec<(Vec<Newline>, Self::PipeableCommand)>,
+    ) -> Result<Self::ListableCommand, Self::Error> {
+        self.inner.pipeline(bang, cmds)
+    }
+
+    fn simple_command(
+        &mut self,
+        redirects_or_env_vars: Vec<ast::RedirectOrEnvVar<Self::Redirect, String, Self::Word>>,
+        redirects_or_cmd_words: Vec<ast::RedirectOrCmdWord<Self::Redirect, Self::Word>>,
+    ) -> Result<Self::PipeableCommand, Self::Error> {
+        self.inner
+            .simple_command(redirects_or_env_vars, redirects_or_cmd_words)
+    }
+
+    fn brace_group(
+        &mut self,
+        cmds: CommandGroup<Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.brace_group(cmds, redirects)
+    }
+
+    fn subshell(
+        &mut self,
+        cmds: CommandGroup<Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.subshell(cmds, redirects)
+    }
+
+    fn loop_command(
+        &mut self,
+        kind: LoopKind,
+        guard_body_pair: GuardBodyPairGroup<Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.loop_command(kind, guard_body_pair, redirects)
+    }
+
+    fn if_command(
+        &mut self,
+        fragments: IfFragments<Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.if_command(fragments, redirects)
+    }
+
+    fn for_command(
+        &mut self,
+        fragments: ForFragments<Self::Word, Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.for_command(fragments, redirects)
+    }
+
+    fn case_command(
+        &mut self,
+        fragments: CaseFragments<Self::Word, Self::Command>,
+        redirects: Vec<Self::Redirect>,
+    ) -> Result<Self::CompoundCommand, Self::Error> {
+        self.inner.case_command(fragments, redirects)
+    }
+
+    fn compound_command_into_pipeable(
+        &mut self,
+        cmd: Self::CompoundCommand,
+    ) -> Result<Self::PipeableCommand, Self::Error> {
+        self.inner.compound_command_into_pipeable(cmd)
+    }
+
+    fn function_declaration(
+        &mut self,
+        name: String,
+        post_name_comments: Vec<Newline>,
+        body: Self::CompoundCommand,
+    ) -> Result<Self::PipeableCommand, Self::Error> {
+        self.inner
+            .function_declaration(name, post_name_comments, body)
+    }
+
+    // Comments ending the script
+    fn comments(&mut self, comments: Vec<Newline>) -> Result<(), Self::Error> {
+        *self.layout.borrow_mut() = Layout {
+            leading: comments.clone(),
+            trailing: None,
+        };
+
+        self.inner.comments(comments)
+    }
+
+    fn word(&mut self, kind: ComplexWordKind<Self::Command>) -> Result<Self::Word, Self::Error> {
+        self.inner.word(kind)
+    }
+
+    fn redirect(&mut self, kind: RedirectKind<Self::Word>) -> Result<Self::Redirect, Self::Error> {
+        self.inner.redirect(kind)
+    }
+}
+
+// A top-level command along with the lines preceding it, either comments or
+// blank, and the comment ending its own line. Comments ending a script come
+// without a command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub leading: Vec<String>,
+    pub command: Option<ast::TopLevelCommand<String>>,
+    pub comment: Option<String>,
+}
+
+fn comment(newline: Newline) -> String {
+    newline.0.unwrap_or_default()
+}
+
+impl Line {
+    // Lay out a serialized command as it was in the script
+    pub fn surround(&self, command: &str) -> Vec<String> {
+        let mut lines = self.leading.clone();
+        let trimmed = command.trim_end_matches('\n');
+
+        match &self.comment {
+            Some(comment) if trimmed.is_empty() => lines.push(comment.clone()),
+            // Heredocs end the command with their bodies, so the comment of their
+            // line is moved above it
+            Some(comment) if trimmed != command => {
+                lines.push(comment.clone());
+                lines.push(trimmed.to_string());
+            }
+            Some(comment) => lines.push(format!("{} {}", command, comment)),
+            None if trimmed.is_empty() => (),
+            None => lines.push(trimmed.to_string()),
+        }
+
+        lines
+    }
+}
+
+// A part of a script that failed to parse: the text from the start of the
+// command to the end of the line holding the error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unparsed {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+    pub snippet: String,
+    pub text: String,
+}
+
+impl fmt::Display for Unparsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Tabs are kept so that the caret lines up with the snippet
+        let margin = self
+            .snippet
+            .chars()
+            .take(self.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        write!(
+            f,
+            "{}:{}: {}\n{}\n{}^",
+            self.line, self.col, self.message, self.snippet, margin
+        )
+    }
+}
+
+impl std::error::Error for Unparsed {}
+
+// Iterator over the top-level commands of a script and their layout. Parsing
+// resumes on the line following an error
+pub struct Lines<'a> {
+    contents: &'a str,
+    // Bytes and lines of the contents before the current parser
+    offset: usize,
+    lines: usize,
+    parser: Option<Parser<Lexer<Chars<'a>>, LayoutBuilder>>,
+    layout: Rc<RefCell<Layout>>,
+    // Commands parsed ahead, on the line being parsed
+    pending: VecDeque<Line>,
+}
+
+impl<'a> Lines<'a> {
+    fn restart(&mut self) {
+        let builder = LayoutBuilder::default();
+        self.layout = builder.layout.clone();
+
+        let rest = &self.contents[self.offset..];
+        self.parser = if rest.is_empty() {
+            None
+        } else {
+            Some(Parser::with_builder(Lexer::new(rest.chars()), builder))
+        };
+    }
+
+    // Describe an error found parsing a command starting at the given position,
+    // then skip to the line following it
+    fn unparsed(
+        &mut self,
+        start: SourcePos,
+        error: ParseError<<Inner as Builder>::Error>,
+    ) -> Unparsed {
+        let rest = &self.contents[self.offset..];
+        let last = rest.lines().count().max(1);
+
+        let (pos, message) = match error {
+            ParseError::BadFd(pos, _) => (Some(pos), String::from("bad file descriptor")),
+            ParseError::BadIdent(name, pos) => (Some(pos), format!("bad identifier `{}`", name)),
+            ParseError::BadSubst(token, pos) => {
+                (Some(pos), format!("bad substitution `{}`", token))
+            }
+            ParseError::Unmatched(token, pos) => (Some(pos), format!("unmatched `{}`", token)),
+            ParseError::IncompleteCmd(cmd, start, keyword, pos) => (
+                Some(pos),
+                format!(
+                    "missing `{}` in `{}` starting on line {}",
+                    keyword,
+                    cmd,
+                    self.lines + start.line
+                ),
+            ),
+            ParseError::Unexpected(Token::Newline, pos) => {
+                (Some(pos), String::from("unexpected end of line"))
+            }
+            ParseError::Unexpected(token, pos) => {
+                (Some(pos), format!("unexpected token `{}`", token))
+            }
+            ParseError::UnexpectedEOF => (None, String::from("unexpected end of file")),
+            ParseError::Custom(never) => match never {},
+        };
+
+        let line = pos.map(|p| p.line).unwrap_or(last).min(last);
+        let col = pos.map(|p| p.col).unwrap_or(1);
+
+        // End of the line holding the error, past its newline
+        let end = rest
+            .match_indices('\n')
+            .nth(line - 1)
+            .map(|(index, _)| index + 1)
+            .unwrap_or(rest.len());
+        let text = &rest[start.byte.min(end)..end];
+
+        let unparsed = Unparsed {
+            line: self.lines + line,
+            col,
+            message,
+            snippet: rest.lines().nth(line - 1).unwrap_or_default().to_string(),
+            text: text.strip_suffix('\n').unwrap_or(text).to_string(),
+        };
+
+        self.lines += line;
+        self.offset += end;
+        self.restart();
+
+        unparsed
+    }
+}
+
+impl Iterator for Lines<'_> {
+    type Item = Result<Line, Unparsed>;
+
+    // Commands are parsed a whole line at a time, so that an error drops all of
+    // the commands on the line it is found on
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
+
+        let start = self.parser.as_ref()?.pos();
+
+        while let Some(parser) = self.parser.as_mut() {
+            let command = match parser.complete_command() {
+                Ok(command) => command,
+                Err(e) => {
+                    self.pending.clear();
+                    return Some(Err(self.unparsed(start, e)));
+                }
+            };
+            let ended = parser.pos().col == 1;
+
+            let layout = self.layout.take();
+            if command.is_none() {
+                self.parser = None;
+
+                if layout.leading.is_empty() {
+                    break;
+                }
+            }
+
+            self.pending.push_back(Line {
+                leading: layout.leading.into_iter().map(comment).collect(),
+                command,
+                comment: layout.trailing.and_then(|newline| newline.0),
+            });
+
+            if ended {
+                break;
+            }
+        }
+
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+pub fn lines(contents: &str) -> Lines<'_> {
+    let mut lines = Lines {
+        contents,
+        offset: 0,
+        lines: 0,
+        parser: None,
+        layout: Rc::default(),
+        pending: VecDeque::new(),
+    };
+
+    lines.restart();
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serializable::Serializable;
+
+    fn layout(script: &str) -> String {
+        lines(script)
+            .map(|line| {
+                let line = line.unwrap();
+                let command = line
+                    .command
+                    .as_ref()
+                    .map(|c| c.into_string())
+                    .unwrap_or_default();
+                line.surround(&command)
+            })
+            .collect::<Vec<Vec<String>>>()
+            .concat()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_lines() {
+        let script = "# Setup\nexport A=1\n\n\n# Build\nmake # all\nmake install\n# end\n";
+        assert_eq!(layout(script), script.trim_end());
+
+        let lines = lines("a; b\nc &\n")
+            .collect::<Result<Vec<Line>, _>>()
+            .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|l| l.leading.is_empty() && l.comment.is_none()));
+    }
+
+    #[test]
+    fn test_lines_heredoc() {
+        assert_eq!(
+            layout("cat <<EOF # input\na\nEOF\n\necho"),
+            "# input\ncat <<'EOF'\na\nEOF\n\necho"
+        );
+    }
+
+    #[test]
+    fn test_lines_error() {
+        let script = "echo a\nif true; then\n\techo ) b\nfi\necho c";
+        let parsed = lines(script).collect::<Vec<_>>();
+
+        // Parsing resumes after the line holding the error
+        assert_eq!(parsed.len(), 4);
+        assert!(parsed[0].is_ok());
+        assert_eq!(
+            parsed[1],
+            Err(Unparsed {
+                line: 3,
+                col: 7,
+                message: String::from("unexpected token `)`"),
+                snippet: String::from("\techo ) b"),
+                text: String::from("if true; then\n\techo ) b"),
+            })
+        );
+        assert_eq!(
+            parsed[1].as_ref().unwrap_err().to_string(),
+            "3:7: unexpected token `)`\n\techo ) b\n\t     ^"
+        );
+        assert!(parsed[2].is_ok());
+        assert!(parsed[3].is_ok());
+
+        let incomplete = lines("echo a\nif true; then\n").collect::<Vec<_>>();
+        assert_eq!(incomplete.len(), 2);
+        assert_eq!(incomplete[1].as_ref().unwrap_err().text, "if true; then");
+        assert_eq!(incomplete[1].as_ref().unwrap_err().line, 2);
+
+        // Commands sharing the line of the error are dropped with it
+        let shared = lines("echo a; echo ) b\necho c").collect::<Vec<_>>();
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared[0].as_ref().unwrap_err().text, "echo a; echo ) b");
+        assert!(shared[1].is_ok());
+    }
+}
@@ -0,0 +1,199 @@
+# Rewrite the lines of spack's shell output setting colon-separated variables to
+# absolute values into modifications relative to their current value, read from
+# the environment, ending them with a semicolon as spack does; the other lines
+# are kept as is. The modifications are shell code with placeholders, given in
+# UNSPACK_PREPEND, UNSPACK_APPEND, UNSPACK_REMOVE and UNSPACK_ENTRY, the part of
+# UNSPACK_REMOVE repeated for each entry removed. The variables named in
+# UNSPACK_SETS are treated as sets, their entries being added unless present
+
+# Replace every occurrence of a placeholder in a text
+function fill(text, placeholder, value,    at, filled) {
+    filled = ""
+    while ((at = index(text, placeholder)) > 0) {
+        filled = filled substr(text, 1, at - 1) value
+        text = substr(text, at + length(placeholder))
+    }
+    return filled text
+}
+
+# Split a colon-separated value into its non-empty entries, returning their count
+function entries(value, list,    parts, count, n, i) {
+    n = split(value, parts, ":")
+    count = 0
+    for (i = 1; i <= n; i++)
+        if (parts[i] != "")
+            list[++count] = parts[i]
+    return count
+}
+
+# Remove the quotes around a value, if it holds no expansion
+function unquote(value) {
+    if (value ~ /^'[^']*'$/ || value ~ /^"[^"$`\\]*"$/)
+        return substr(value, 2, length(value) - 2)
+    return value
+}
+
+# Print a modification of a variable, its entries being at the given positions
+function modify(template, name, list, from, to,    joined, i) {
+    if (from >= to)
+        return
+    joined = ""
+    for (i = from; i < to; i++)
+        joined = joined (i > from ? ":" : "") list[i]
+    print fill(fill(template, "@NAME@", name), "@ENTRIES@", joined) ";"
+}
+
+# Print the removal of entries from a variable
+function remove(name, list, count,    chunks, chunk, i) {
+    if (count == 0)
+        return
+    chunks = ""
+    for (i = 1; i <= count; i++) {
+        chunk = fill(ENVIRON["UNSPACK_ENTRY"], "@ENTRY@", list[i])
+        chunks = chunks (i > 1 ? " " : "") chunk
+    }
+    print fill(fill(ENVIRON["UNSPACK_REMOVE"], "@NAME@", name), ENVIRON["UNSPACK_ENTRY"], chunks) ";"
+}
+
+# Print the modifications turning the old value of a list into the new one, as
+# entries removed from it, then added around the rest; the rest is a run of new
+# entries found in the same order in the old value, holding every occurrence of
+# its entries there, as removing the others would remove them as well. Returns 0
+# if no part of the old value can be kept
+function relative(name, old, value,    o, n, m, k, i, e, occ, pos, cnt, run, first, last, missing, single, from, found, start, end, kept, removed, count) {
+    m = entries(old, o)
+    k = entries(value, n)
+
+    if (m == 0) {
+        modify(ENVIRON["UNSPACK_PREPEND"], name, n, 1, k + 1)
+        return 1
+    }
+
+    for (i = 1; i <= m; i++)
+        pos[o[i], ++occ[o[i]]] = i
+
+    run = 0
+    found = 0
+    for (i = 1; i <= k; i++) {
+        e = n[i]
+        if (!(e in occ)) {
+            run = 0
+            continue
+        }
+
+        if (!(run && cnt[e] + 0 < occ[e] && pos[e, cnt[e] + 1] > last)) {
+            run = 1
+            first = i
+            split("", cnt)
+            missing = 0
+            single = i
+        }
+
+        last = pos[e, ++cnt[e]]
+        if (cnt[e] == 1)
+            missing++
+        if (cnt[e] == occ[e])
+            missing--
+        if (occ[e] > 1)
+            single = i + 1
+
+        from = missing == 0 ? first : single
+        if (from <= i && (!found || i + 1 - from > end - start)) {
+            found = 1
+            start = from
+            end = i + 1
+        }
+    }
+
+    if (!found)
+        return 0
+
+    for (i = start; i < end; i++)
+        kept[n[i]] = 1
+
+    count = 0
+    for (i = 1; i <= m; i++)
+        if (!(o[i] in kept))
+            removed[++count] = o[i]
+
+    remove(name, removed, count)
+    modify(ENVIRON["UNSPACK_PREPEND"], name, n, 1, start)
+    modify(ENVIRON["UNSPACK_APPEND"], name, n, end, k + 1)
+    return 1
+}
+
+# Print the modifications turning the old value of a set into the new one, as
+# the entries removed from it, then the entries added if they are missing
+function set(name, old, value,    o, n, m, k, i, seen, removed, added, count) {
+    m = entries(old, o)
+    k = entries(value, n)
+
+    for (i = 1; i <= k; i++)
+        seen[n[i]] = 1
+    count = 0
+    for (i = 1; i <= m; i++)
+        if (!(o[i] in seen))
+            removed[++count] = o[i]
+    remove(name, removed, count)
+
+    split("", seen)
+    for (i = 1; i <= m; i++)
+        seen[o[i]] = 1
+    count = 0
+    for (i = 1; i <= k; i++)
+        if (!(n[i] in seen))
+            added[++count] = n[i]
+    remove(name, added, count)
+    modify(ENVIRON["UNSPACK_APPEND"], name, added, 1, count + 1)
+}
+
+BEGIN {
+    split(ENVIRON["UNSPACK_SETS"], names, " ")
+    for (i in names)
+        sets[names[i]] = 1
+}
+
+{
+    line = $0
+    sub(/[ \t]*;?[ \t]*$/, "", line)
+
+    # Exports of sh, setenv of csh and set -gx of fish, which lists values
+    list = 0
+    if (match(line, /^(export [A-Za-z_][A-Za-z0-9_]*=|setenv [A-Za-z_][A-Za-z0-9_]* )/)) {
+        name = substr(line, 8, RLENGTH - 8)
+    } else if (match(line, /^set -gx [A-Za-z_][A-Za-z0-9_]* /)) {
+        name = substr(line, 9, RLENGTH - 9)
+        list = 1
+    } else {
+        print
+        next
+    }
+
+    if (name !~ /PATH$/ && !(name in sets)) {
+        print
+        next
+    }
+
+    count = split(substr(line, RLENGTH + 1), values, " ")
+    if (count != 1 && !list) {
+        print
+        next
+    }
+
+    value = ""
+    for (i = 1; i <= count; i++)
+        value = value (i > 1 ? ":" : "") unquote(values[i])
+
+    # Entries are written unquoted in the modifications
+    if (value !~ /^[-A-Za-z0-9_.\/+,%=:]*$/) {
+        print
+        next
+    }
+
+    if (ENVIRON[name] == value)
+        next
+    if (name in sets)
+        set(name, ENVIRON[name], value)
+    else if (!relative(name, ENVIRON[name], value))
+        print
+}
@@ -1,509 +1,1222 @@
 use conch_parser::ast;
+use std::fmt::{self, Write};
+use std::io;
 use std::rc::Rc;
 
+// Serialization into any sink, without allocating the output of every node
+pub trait SerializeTo {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result;
+
+    // Serialize a command that other commands follow on the same line
+    fn serialize_line_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.serialize_to(out)
+    }
+
+    // Serialize into a text sink, such as a String or a formatter
+    fn serialize_fmt<W: Write + ?Sized>(&self, mut out: &mut W) -> fmt::Result {
+        let mut placed = Heredocs::new(&mut out);
+        self.serialize_to(&mut placed)?;
+        placed.complete()
+    }
+
+    // Serialize into a byte sink, such as a file or the standard output
+    fn serialize_io<O: io::Write + ?Sized>(&self, out: &mut O) -> io::Result<()> {
+        let mut adapter = IoAdapter {
+            inner: out,
+            error: Ok(()),
+        };
+
+        match self.serialize_fmt(&mut adapter) {
+            Ok(()) => Ok(()),
+            Err(_) => adapter.error.and(Err(io::Error::other("formatter error"))),
+        }
+    }
+}
+
+// Forwards text to a byte sink, keeping the error the formatting machinery drops
+struct IoAdapter<'a, O: io::Write + ?Sized> {
+    inner: &'a mut O,
+    error: io::Result<()>,
+}
+
+impl<O: io::Write + ?Sized> Write for IoAdapter<'_, O> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Err(e);
+            fmt::Error
+        })
+    }
+}
+
+#[allow(clippy::wrong_self_convention)]
 pub trait Serializable<S> {
     fn into_string(&self) -> S;
 }
 
-impl Serializable<String> for String {
+impl<T: SerializeTo + ?Sized> Serializable<String> for T {
     fn into_string(&self) -> String {
-        String::from(self)
+        let mut string = String::new();
+        self.serialize_fmt(&mut string)
+            .expect("a String cannot fail to be written");
+        string
     }
 }
 
-impl<B: Serializable<String>> Serializable<String> for Box<B> {
-    fn into_string(&self) -> String {
-        (**self).into_string()
+impl SerializeTo for String {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        out.write_str(self)
     }
 }
 
-impl<B: Serializable<String>> Serializable<String> for Rc<B> {
-    fn into_string(&self) -> String {
-        (**self).into_string()
+impl<B: SerializeTo> SerializeTo for Box<B> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        (**self).serialize_to(out)
+    }
+
+    fn serialize_line_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        (**self).serialize_line_to(out)
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::TopLevelCommand<S> {
-    fn into_string(&self) -> String {
-        self.0.into_string()
+impl<B: SerializeTo> SerializeTo for Rc<B> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        (**self).serialize_to(out)
+    }
+
+    fn serialize_line_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        (**self).serialize_line_to(out)
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::TopLevelWord<S> {
-    fn into_string(&self) -> String {
-        self.0.into_string()
+impl SerializeTo for ast::TopLevelCommand<String> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.0.serialize_to(out)?;
+        out.complete()
+    }
+
+    // Heredoc bodies are left for the end of the line
+    fn serialize_line_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.0.serialize_to(out)
     }
 }
 
-impl<T: Serializable<String>> Serializable<String> for ast::Command<T> {
-    fn into_string(&self) -> String {
-        match self {
-            ast::Command::Job(list) => format!("{} &", list.into_string()),
-            ast::Command::List(list) => list.into_string(),
+// A sink for serialized text, keeping track of the lines that heredoc bodies must
+// follow
+pub trait Output: Write {
+    // The sink of the heredoc bodies to write after the current line
+    fn deferred(&mut self) -> Result<&mut dyn Write, fmt::Error>;
+
+    // Write text whose newlines do not end the current line, such as the
+    // commands of a substitution
+    fn write_nested(&mut self, s: &str) -> fmt::Result;
+
+    // End the current line if heredoc bodies must follow it, and write them
+    fn complete(&mut self) -> fmt::Result;
+}
+
+// Writer moving the heredoc bodies of a command after the end of the line they
+// appear on; a command ending with a heredoc ends with a newline
+struct Heredocs<'a> {
+    out: &'a mut dyn Write,
+    pending: String,
+}
+
+impl<'a> Heredocs<'a> {
+    fn new(out: &'a mut dyn Write) -> Self {
+        Heredocs {
+            out,
+            pending: String::new(),
         }
     }
 }
 
-impl<L: Serializable<String>, P: Serializable<String>, S: Serializable<String>> Serializable<String>
-    for ast::SimpleWord<L, P, S>
-{
-    fn into_string(&self) -> String {
-        match self {
-            ast::SimpleWord::Literal(l) => l.into_string(),
-            ast::SimpleWord::Escaped(e) => format!("\\{}", e.into_string()),
-            ast::SimpleWord::Star => String::from("*"),
-            ast::SimpleWord::Question => String::from("?"),
-            ast::SimpleWord::SquareOpen => String::from("["),
-            ast::SimpleWord::SquareClose => String::from("]"),
-            ast::SimpleWord::Tilde => String::from("~"),
-            ast::SimpleWord::Colon => String::from(":"),
-            ast::SimpleWord::Param(value) => value.into_string(),
-            ast::SimpleWord::Subst(value) => value.into_string(),
-        }
-    }
-}
-
-macro_rules! substitution {
-    // I'm sorry
-    ($colon:expr, $prefix:expr, $suffix:expr, $symbol:expr) => {
-        match ($colon, $prefix, $suffix) {
-            (true, ast::Parameter::Var(v), Some(value)) => format!(
-                "${{{}:{}{}}}",
-                v.into_string(),
-                $symbol,
-                value.into_string()
-            ),
-            (true, ast::Parameter::Var(v), None) => {
-                format!("${{{}:{}}}", v.into_string(), $symbol)
-            }
-            (true, v, Some(value)) => format!(
-                "${{{}:{}{}}}",
-                v.into_string(),
-                $symbol,
-                value.into_string()
-            ),
-            (true, v, None) => {
-                format!("${{{}:{}}}", v.into_string(), $symbol)
+impl Write for Heredocs<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (index, line) in s.split('\n').enumerate() {
+            if index > 0 {
+                self.out.write_char('\n')?;
+                self.out.write_str(&self.pending)?;
+                self.pending.clear();
             }
-            (false, ast::Parameter::Var(v), Some(value)) => {
-                format!("${{{}{}{}}}", v.into_string(), $symbol, value.into_string())
-            }
-            (false, ast::Parameter::Var(v), None) => {
-                format!("${{{}{}}}", v.into_string(), $symbol)
-            }
-            (false, v, Some(value)) => {
-                format!("${{{}{}{}}}", v.into_string(), $symbol, value.into_string())
+            self.out.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Output for Heredocs<'_> {
+    fn deferred(&mut self) -> Result<&mut dyn Write, fmt::Error> {
+        Ok(&mut self.pending)
+    }
+
+    fn write_nested(&mut self, s: &str) -> fmt::Result {
+        self.out.write_str(s)
+    }
+
+    fn complete(&mut self) -> fmt::Result {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.out.write_char('\n')?;
+        self.out.write_str(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl SerializeTo for ast::TopLevelWord<String> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.0.serialize_to(out)
+    }
+}
+
+impl<T: SerializeTo> SerializeTo for ast::Command<T> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        match self {
+            ast::Command::Job(list) => {
+                list.serialize_to(out)?;
+                out.write_str(" &")
             }
-            (false, v, None) => {
-                format!("${{{}{}}}", v.into_string(), $symbol)
+            ast::Command::List(list) => list.serialize_to(out),
+        }
+    }
+}
+
+impl<L: SerializeTo, P: SerializeTo, S: SerializeTo> SerializeTo for ast::SimpleWord<L, P, S> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        match self {
+            ast::SimpleWord::Literal(l) => l.serialize_to(out),
+            ast::SimpleWord::Escaped(e) => {
+                out.write_char('\\')?;
+                e.serialize_to(out)
             }
+            ast::SimpleWord::Star => out.write_str("*"),
+            ast::SimpleWord::Question => out.write_str("?"),
+            ast::SimpleWord::SquareOpen => out.write_str("["),
+            ast::SimpleWord::SquareClose => out.write_str("]"),
+            ast::SimpleWord::Tilde => out.write_str("~"),
+            ast::SimpleWord::Colon => out.write_str(":"),
+            ast::SimpleWord::Param(value) => value.serialize_to(out),
+            ast::SimpleWord::Subst(value) => value.serialize_to(out),
         }
-    };
+    }
 }
 
-macro_rules! join {
-    // Iter over vec, serialize contents and join using the provided separator
-    ($vec:expr, $sep:expr) => {
-        $vec.iter()
-            .map(|x| x.into_string())
-            .collect::<Vec<String>>()
-            .join($sep)
-    };
+// Serialize the items of a slice, separated by the provided string
+fn join<O: Output, T: SerializeTo>(out: &mut O, items: &[T], separator: &str) -> fmt::Result {
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            out.write_str(separator)?;
+        }
+        item.serialize_to(out)?;
+    }
+
+    Ok(())
+}
+
+// Return the name of a parameter, as written in a substitution
+fn bare<P: SerializeTo>(parameter: &ast::Parameter<P>) -> String {
+    match parameter {
+        ast::Parameter::Var(name) => name.into_string(),
+        ast::Parameter::Positional(index) => index.to_string(),
+        p => p.into_string().split_off(1),
+    }
+}
+
+// Write ${parameter[:]<symbol>[word]}
+fn substitution<O: Output, P: SerializeTo, W: SerializeTo>(
+    out: &mut O,
+    colon: bool,
+    parameter: &ast::Parameter<P>,
+    word: &Option<W>,
+    symbol: &str,
+) -> fmt::Result {
+    write!(
+        out,
+        "${{{}{}{}",
+        bare(parameter),
+        if colon { ":" } else { "" },
+        symbol
+    )?;
+    if let Some(word) = word {
+        word.serialize_to(out)?;
+    }
+    out.write_char('}')
+}
+
+// Remembers whether the last thing written was a background job ampersand
+struct Tail<'a> {
+    out: &'a mut dyn Output,
+    last: [char; 2],
+}
+
+impl<'a> Tail<'a> {
+    fn new(out: &'a mut dyn Output) -> Self {
+        Tail {
+            out,
+            last: ['\0'; 2],
+        }
+    }
+
+    fn is_job(&self) -> bool {
+        self.last == [' ', '&']
+    }
+}
+
+impl Tail<'_> {
+    fn track(&mut self, s: &str) {
+        for c in s.chars() {
+            self.last = [self.last[1], c];
+        }
+    }
+}
+
+impl Write for Tail<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.track(s);
+        self.out.write_str(s)
+    }
+}
+
+impl Output for Tail<'_> {
+    fn deferred(&mut self) -> Result<&mut dyn Write, fmt::Error> {
+        self.out.deferred()
+    }
+
+    fn write_nested(&mut self, s: &str) -> fmt::Result {
+        self.track(s);
+        self.out.write_nested(s)
+    }
+
+    fn complete(&mut self) -> fmt::Result {
+        self.out.complete()
+    }
+}
+
+// Writer opening a command substitution with its first character, as a leading
+// subshell must not be mistaken for arithmetic; the commands are nested in the
+// enclosing output, their heredocs being placed within the substitution
+struct Substitution<'a> {
+    out: &'a mut dyn Output,
+    opened: bool,
 }
 
-impl<
-        P: Serializable<String>,
-        W: Serializable<String>,
-        C: Serializable<String>,
-        A: Serializable<String>,
-    > Serializable<String> for ast::ParameterSubstitution<ast::Parameter<P>, W, C, A>
+impl Write for Substitution<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.opened && !s.is_empty() {
+            self.out
+                .write_str(if s.starts_with('(') { "$( " } else { "$(" })?;
+            self.opened = true;
+        }
+
+        self.out.write_nested(s)
+    }
+}
+
+// Serialize a sequence of commands on a single line; the last one is terminated
+// if more code follows. Background jobs are already terminated by their ampersand
+fn separate<O: Output, C: SerializeTo>(out: &mut O, cmds: &[C], terminated: bool) -> fmt::Result {
+    for (index, cmd) in cmds.iter().enumerate() {
+        if index > 0 {
+            out.write_char(' ')?;
+        }
+
+        let mut tail = Tail::new(&mut *out);
+        cmd.serialize_line_to(&mut tail)?;
+
+        if (terminated || index + 1 < cmds.len()) && !tail.is_job() {
+            out.write_char(';')?;
+        }
+    }
+
+    Ok(())
+}
+
+impl<P: SerializeTo, W: SerializeTo, C: SerializeTo, A: SerializeTo> SerializeTo
+    for ast::ParameterSubstitution<ast::Parameter<P>, W, C, A>
 {
-    fn into_string(&self) -> String {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
             ast::ParameterSubstitution::Command(vec) => {
-                format!("$({})", join!(vec, " "))
-            }
-            ast::ParameterSubstitution::Len(ast::Parameter::Var(value)) => {
-                format!("${{#{}}}", value.into_string())
+                let mut substitution = Substitution { out, opened: false };
+                let mut placed = Heredocs::new(&mut substitution);
+                separate(&mut placed, vec, false)?;
+                placed.complete()?;
+
+                if !substitution.opened {
+                    substitution.out.write_str("$(")?;
+                }
+                substitution.out.write_char(')')
             }
-            ast::ParameterSubstitution::Len(value) => {
-                format!("${{#{}}}", value.into_string())
+            ast::ParameterSubstitution::Len(parameter) => write!(out, "${{#{}}}", bare(parameter)),
+            ast::ParameterSubstitution::Arith(option) => {
+                out.write_str("$((")?;
+                if let Some(value) = option {
+                    value.serialize_to(out)?;
+                }
+                out.write_str("))")
             }
-            ast::ParameterSubstitution::Arith(option) => match option {
-                Some(value) => format!("$(({}))", value.into_string()),
-                None => String::from("$(())"),
-            },
             ast::ParameterSubstitution::Default(colon, prefix, suffix) => {
-                substitution!(colon, prefix, suffix, "-")
+                substitution(out, *colon, prefix, suffix, "-")
             }
             ast::ParameterSubstitution::Assign(colon, prefix, suffix) => {
-                substitution!(colon, prefix, suffix, "=")
+                substitution(out, *colon, prefix, suffix, "=")
             }
             ast::ParameterSubstitution::Error(colon, prefix, suffix) => {
-                substitution!(colon, prefix, suffix, "?")
+                substitution(out, *colon, prefix, suffix, "?")
             }
             ast::ParameterSubstitution::Alternative(colon, prefix, suffix) => {
-                substitution!(colon, prefix, suffix, "+")
+                substitution(out, *colon, prefix, suffix, "+")
             }
             ast::ParameterSubstitution::RemoveSmallestPrefix(prefix, suffix) => {
-                substitution!(false, prefix, suffix, "%")
+                substitution(out, false, prefix, suffix, "#")
             }
             ast::ParameterSubstitution::RemoveLargestPrefix(prefix, suffix) => {
-                substitution!(false, prefix, suffix, "%%")
+                substitution(out, false, prefix, suffix, "##")
             }
             ast::ParameterSubstitution::RemoveSmallestSuffix(prefix, suffix) => {
-                substitution!(false, prefix, suffix, "#")
+                substitution(out, false, prefix, suffix, "%")
             }
             ast::ParameterSubstitution::RemoveLargestSuffix(prefix, suffix) => {
-                substitution!(false, prefix, suffix, "##")
+                substitution(out, false, prefix, suffix, "%%")
             }
         }
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::Arithmetic<S> {
-    fn into_string(&self) -> String {
+// Parenthesize compound operands, as the tree does not hold the precedence
+// they were written with
+fn operand<O: Output, S: SerializeTo>(out: &mut O, value: &ast::Arithmetic<S>) -> fmt::Result {
+    match value {
+        ast::Arithmetic::Var(_) | ast::Arithmetic::Literal(_) => value.serialize_to(out),
+        value => {
+            out.write_char('(')?;
+            value.serialize_to(out)?;
+            out.write_char(')')
+        }
+    }
+}
+
+// Write a binary arithmetic operation
+fn binary<O: Output, S: SerializeTo>(
+    out: &mut O,
+    lhs: &ast::Arithmetic<S>,
+    operator: &str,
+    rhs: &ast::Arithmetic<S>,
+) -> fmt::Result {
+    operand(out, lhs)?;
+    write!(out, " {} ", operator)?;
+    operand(out, rhs)
+}
+
+impl<S: SerializeTo> SerializeTo for ast::Arithmetic<S> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::Arithmetic::Var(value) => value.into_string(),
-            ast::Arithmetic::Literal(value) => format!("{}", value),
-            ast::Arithmetic::Pow(lhs, rhs) => {
-                format!("{} ** {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::Var(value) => value.serialize_to(out),
+            ast::Arithmetic::Literal(value) => write!(out, "{}", value),
+            ast::Arithmetic::Pow(lhs, rhs) => binary(out, lhs, "**", rhs),
+            ast::Arithmetic::PostIncr(value) => {
+                value.serialize_to(out)?;
+                out.write_str("++")
             }
-            ast::Arithmetic::PostIncr(value) => format!("{}++", value.into_string()),
-            ast::Arithmetic::PostDecr(value) => format!("{}--", value.into_string()),
-            ast::Arithmetic::PreIncr(value) => format!("++{}", value.into_string()),
-            ast::Arithmetic::PreDecr(value) => format!("--{}", value.into_string()),
-            ast::Arithmetic::UnaryPlus(value) => format!("+({})", value.into_string()),
-            ast::Arithmetic::UnaryMinus(value) => format!("-({})", value.into_string()),
-            ast::Arithmetic::LogicalNot(value) => format!("!{}", value.into_string()),
-            ast::Arithmetic::BitwiseNot(value) => format!("~{}", value.into_string()),
-            ast::Arithmetic::Mult(lhs, rhs) => {
-                format!("{} * {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::PostDecr(value) => {
+                value.serialize_to(out)?;
+                out.write_str("--")
             }
-            ast::Arithmetic::Div(lhs, rhs) => {
-                format!("{} / {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::PreIncr(value) => {
+                out.write_str("++")?;
+                value.serialize_to(out)
             }
-            ast::Arithmetic::Modulo(lhs, rhs) => {
-                format!("{} % {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::PreDecr(value) => {
+                out.write_str("--")?;
+                value.serialize_to(out)
             }
-            ast::Arithmetic::Add(lhs, rhs) => {
-                format!("{} + {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::UnaryPlus(value) => {
+                out.write_str("+(")?;
+                value.serialize_to(out)?;
+                out.write_char(')')
             }
-            ast::Arithmetic::Sub(lhs, rhs) => {
-                format!("{} - {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::UnaryMinus(value) => {
+                out.write_str("-(")?;
+                value.serialize_to(out)?;
+                out.write_char(')')
             }
-            ast::Arithmetic::ShiftLeft(lhs, rhs) => {
-                format!("{} << {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::LogicalNot(value) => {
+                out.write_char('!')?;
+                operand(out, value)
             }
-            ast::Arithmetic::ShiftRight(lhs, rhs) => {
-                format!("{} >> {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::Less(lhs, rhs) => {
-                format!("{} < {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::LessEq(lhs, rhs) => {
-                format!("{} <= {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::Great(lhs, rhs) => {
-                format!("{} > {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::GreatEq(lhs, rhs) => {
-                format!("{} >= {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::Eq(lhs, rhs) => {
-                format!("{} == {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::NotEq(lhs, rhs) => {
-                format!("{} != {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::BitwiseAnd(lhs, rhs) => {
-                format!("{} & {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::BitwiseXor(lhs, rhs) => {
-                format!("{} ^ {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::BitwiseOr(lhs, rhs) => {
-                format!("{} | {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::LogicalAnd(lhs, rhs) => {
-                format!("{} && {}", lhs.into_string(), rhs.into_string())
-            }
-            ast::Arithmetic::LogicalOr(lhs, rhs) => {
-                format!("{} || {}", lhs.into_string(), rhs.into_string())
+            ast::Arithmetic::BitwiseNot(value) => {
+                out.write_char('~')?;
+                operand(out, value)
             }
+            ast::Arithmetic::Mult(lhs, rhs) => binary(out, lhs, "*", rhs),
+            ast::Arithmetic::Div(lhs, rhs) => binary(out, lhs, "/", rhs),
+            ast::Arithmetic::Modulo(lhs, rhs) => binary(out, lhs, "%", rhs),
+            ast::Arithmetic::Add(lhs, rhs) => binary(out, lhs, "+", rhs),
+            ast::Arithmetic::Sub(lhs, rhs) => binary(out, lhs, "-", rhs),
+            ast::Arithmetic::ShiftLeft(lhs, rhs) => binary(out, lhs, "<<", rhs),
+            ast::Arithmetic::ShiftRight(lhs, rhs) => binary(out, lhs, ">>", rhs),
+            ast::Arithmetic::Less(lhs, rhs) => binary(out, lhs, "<", rhs),
+            ast::Arithmetic::LessEq(lhs, rhs) => binary(out, lhs, "<=", rhs),
+            ast::Arithmetic::Great(lhs, rhs) => binary(out, lhs, ">", rhs),
+            ast::Arithmetic::GreatEq(lhs, rhs) => binary(out, lhs, ">=", rhs),
+            ast::Arithmetic::Eq(lhs, rhs) => binary(out, lhs, "==", rhs),
+            ast::Arithmetic::NotEq(lhs, rhs) => binary(out, lhs, "!=", rhs),
+            ast::Arithmetic::BitwiseAnd(lhs, rhs) => binary(out, lhs, "&", rhs),
+            ast::Arithmetic::BitwiseXor(lhs, rhs) => binary(out, lhs, "^", rhs),
+            ast::Arithmetic::BitwiseOr(lhs, rhs) => binary(out, lhs, "|", rhs),
+            ast::Arithmetic::LogicalAnd(lhs, rhs) => binary(out, lhs, "&&", rhs),
+            ast::Arithmetic::LogicalOr(lhs, rhs) => binary(out, lhs, "||", rhs),
             ast::Arithmetic::Ternary(cond, lhs, rhs) => {
-                format!(
-                    "{} ? {} : {}",
-                    cond.into_string(),
-                    lhs.into_string(),
-                    rhs.into_string()
-                )
+                operand(out, cond)?;
+                out.write_str(" ? ")?;
+                operand(out, lhs)?;
+                out.write_str(" : ")?;
+                operand(out, rhs)
             }
             ast::Arithmetic::Assign(ident, value) => {
-                format!("{} = {}", ident.into_string(), value.into_string(),)
+                ident.serialize_to(out)?;
+                out.write_str(" = ")?;
+                operand(out, value)
             }
             ast::Arithmetic::Sequence(values) => {
-                join!(values, ", ")
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        out.write_str(", ")?;
+                    }
+                    operand(out, value)?;
+                }
+                Ok(())
             }
         }
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::AndOr<S> {
-    fn into_string(&self) -> String {
+impl<S: SerializeTo> SerializeTo for ast::AndOr<S> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::AndOr::And(dlc) => format!("&& {}", dlc.into_string()),
-            ast::AndOr::Or(dlc) => format!("|| {}", dlc.into_string()),
+            ast::AndOr::And(dlc) => {
+                out.write_str("&& ")?;
+                dlc.serialize_to(out)
+            }
+            ast::AndOr::Or(dlc) => {
+                out.write_str("|| ")?;
+                dlc.serialize_to(out)
+            }
         }
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::Parameter<S> {
-    fn into_string(&self) -> String {
+impl<S: SerializeTo> SerializeTo for ast::Parameter<S> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::Parameter::At => String::from("$@"),
-            ast::Parameter::Star => String::from("$*"),
-            ast::Parameter::Question => String::from("$?"),
-            ast::Parameter::Pound => String::from("$#"),
-            ast::Parameter::Dash => String::from("$-"),
-            ast::Parameter::Dollar => String::from("$$"),
-            ast::Parameter::Bang => String::from("$!"),
-            ast::Parameter::Positional(value) => format!("${}", value),
-            ast::Parameter::Var(value) => format!("${}", value.into_string()),
+            ast::Parameter::At => out.write_str("$@"),
+            ast::Parameter::Star => out.write_str("$*"),
+            ast::Parameter::Question => out.write_str("$?"),
+            ast::Parameter::Pound => out.write_str("$#"),
+            ast::Parameter::Dash => out.write_str("$-"),
+            ast::Parameter::Dollar => out.write_str("$$"),
+            ast::Parameter::Bang => out.write_str("$!"),
+            ast::Parameter::Positional(value) if *value > 9 => write!(out, "${{{}}}", value),
+            ast::Parameter::Positional(value) => write!(out, "${}", value),
+            ast::Parameter::Var(value) => {
+                out.write_char('$')?;
+                value.serialize_to(out)
+            }
         }
     }
 }
 
-impl<S: Serializable<String>> Serializable<String> for ast::ComplexWord<S> {
-    fn into_string(&self) -> String {
+// Return true if the character would be read as part of a variable name
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Writer keeping the start of a word as long as it may be a variable such as
+// $name; the serialization stops at the first character ruling it out
+struct Peek {
+    text: String,
+}
+
+impl Write for Peek {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let fits = match self.text.as_str() {
+                "" => c == '$',
+                _ => is_name_char(c),
+            };
+
+            self.text.push(c);
+            if !fits {
+                return Err(fmt::Error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Output for Peek {
+    fn deferred(&mut self) -> Result<&mut dyn Write, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn write_nested(&mut self, s: &str) -> fmt::Result {
+        self.write_str(s)
+    }
+
+    fn complete(&mut self) -> fmt::Result {
+        Ok(())
+    }
+}
+
+// Return the first character of a word, and its name if it is a variable
+fn peek<T: SerializeTo>(word: &T) -> (Option<char>, Option<String>) {
+    let mut peek = Peek {
+        text: String::new(),
+    };
+    let whole = word.serialize_to(&mut peek).is_ok();
+
+    let name = peek
+        .text
+        .strip_prefix('$')
+        .filter(|name| whole && name.starts_with(|c: char| !c.is_ascii_digit()))
+        .map(String::from);
+
+    (peek.text.chars().next(), name)
+}
+
+// Concatenate words, bracing variables that would otherwise absorb the
+// characters following them; only the start of each word is looked at ahead
+fn concat<O: Output, T: SerializeTo>(out: &mut O, words: &[T]) -> fmt::Result {
+    for (index, word) in words.iter().enumerate() {
+        let absorbs = words
+            .get(index + 1)
+            .and_then(|next| peek(next).0)
+            .is_some_and(is_name_char);
+
+        match peek(word).1 {
+            Some(name) if absorbs => write!(out, "${{{}}}", name)?,
+            _ => word.serialize_to(out)?,
+        }
+    }
+
+    Ok(())
+}
+
+impl<S: SerializeTo> SerializeTo for ast::ComplexWord<S> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::ComplexWord::Single(w) => w.into_string(),
-            ast::ComplexWord::Concat(vec) => join!(vec, ""),
+            ast::ComplexWord::Single(w) => w.serialize_to(out),
+            ast::ComplexWord::Concat(vec) => concat(out, vec),
         }
     }
 }
 
-impl<L: Serializable<String>, W: Serializable<String>> Serializable<String> for ast::Word<L, W> {
-    fn into_string(&self) -> String {
+impl<L: SerializeTo, W: SerializeTo> SerializeTo for ast::Word<L, W> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::Word::Simple(w) => w.into_string(),
-            ast::Word::DoubleQuoted(w) => format!("\"{}\"", join!(w, "")),
-            ast::Word::SingleQuoted(w) => w.into_string(),
+            ast::Word::Simple(w) => w.serialize_to(out),
+            ast::Word::DoubleQuoted(w) => {
+                out.write_char('"')?;
+                concat(out, w)?;
+                out.write_char('"')
+            }
+            // Single quotes cannot be escaped, so the contents never hold one
+            ast::Word::SingleQuoted(w) => {
+                out.write_char('\'')?;
+                w.serialize_to(out)?;
+                out.write_char('\'')
+            }
         }
     }
 }
 
-impl<V: Serializable<String>, W: Serializable<String>, C: Serializable<String>> Serializable<String>
+impl<V: SerializeTo, W: SerializeTo, C: SerializeTo> SerializeTo
     for ast::CompoundCommandKind<V, W, C>
 {
-    fn into_string(&self) -> String {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::CompoundCommandKind::Brace(vec) => format!("{{ {}; }}", join!(vec, "; ")),
-            ast::CompoundCommandKind::Subshell(vec) => format!("( {} )", join!(vec, "; ")),
+            ast::CompoundCommandKind::Brace(vec) => {
+                out.write_str("{ ")?;
+                separate(out, vec, true)?;
+                out.write_str(" }")
+            }
+            ast::CompoundCommandKind::Subshell(vec) => {
+                out.write_str("( ")?;
+                separate(out, vec, false)?;
+                out.write_str(" )")
+            }
             ast::CompoundCommandKind::While(gbp) => {
-                let guard = join!(gbp.guard, "; ");
-                let body = join!(gbp.body, "\n");
-                format!("while {}; do\n{}\ndone", guard, body)
+                out.write_str("while ")?;
+                separate(out, &gbp.guard, true)?;
+                out.write_str(" do\n")?;
+                join(out, &gbp.body, "\n")?;
+                out.write_str("\ndone")
             }
             ast::CompoundCommandKind::Until(gbp) => {
-                let guard = join!(gbp.guard, "; ");
-                let body = join!(gbp.body, "\n");
-                format!("until {}; do\n{}\ndone", guard, body)
+                out.write_str("until ")?;
+                separate(out, &gbp.guard, true)?;
+                out.write_str(" do\n")?;
+                join(out, &gbp.body, "\n")?;
+                out.write_str("\ndone")
             }
             ast::CompoundCommandKind::If {
                 conditionals,
                 else_branch,
             } => {
                 // conditionals is a list of all conditions,actions of a if/elif list - we proceed
-                // by formatting them all the same way - as a regular if - and then join them using
-                // the "el" delimiter, transforming all but the first member into elifs
-                let cases = conditionals
-                    .iter()
-                    .map(|gbp| {
-                        let guard = join!(gbp.guard, "; ");
-                        let body = join!(gbp.body, "\n");
-                        format!("if {}; then\n{}", guard, body)
-                    })
-                    .collect::<Vec<String>>();
+                // by formatting them all the same way - as a regular if - and then prefix all but
+                // the first with the "el" delimiter, transforming them into elifs
+                for (index, gbp) in conditionals.iter().enumerate() {
+                    if index > 0 {
+                        out.write_str("\nel")?;
+                    }
+                    out.write_str("if ")?;
+                    separate(out, &gbp.guard, true)?;
+                    out.write_str(" then\n")?;
+                    join(out, &gbp.body, "\n")?;
+                }
 
                 if let Some(statements) = else_branch {
-                    format!(
-                        "{}\nelse\n{}\nfi",
-                        join!(cases, "\nel"),
-                        join!(statements, "\n")
-                    )
-                } else {
-                    format!("{}\nfi", join!(cases, "\nel"))
+                    out.write_str("\nelse\n")?;
+                    join(out, statements, "\n")?;
                 }
+
+                out.write_str("\nfi")
             }
             ast::CompoundCommandKind::For { var, words, body } => {
+                out.write_str("for ")?;
+                var.serialize_to(out)?;
                 if let Some(word_v) = words {
-                    format!(
-                        "for {} in {}; do\n{}\ndone",
-                        var.into_string(),
-                        join!(word_v, " "),
-                        join!(body, "\n")
-                    )
-                } else {
-                    format!("for {}; do\n{}\ndone", var.into_string(), join!(body, "\n"))
+                    out.write_str(" in ")?;
+                    join(out, word_v, " ")?;
                 }
+                out.write_str("; do\n")?;
+                join(out, body, "\n")?;
+                out.write_str("\ndone")
             }
             ast::CompoundCommandKind::Case { word, arms } => {
-                format!("case {} in {}\nesac", word.into_string(), join!(arms, "\n"))
+                out.write_str("case ")?;
+                word.serialize_to(out)?;
+                out.write_str(" in ")?;
+                join(out, arms, "\n")?;
+                out.write_str("\nesac")
             }
         }
     }
 }
 
-impl<W: Serializable<String>, C: Serializable<String>> Serializable<String>
-    for ast::PatternBodyPair<W, C>
-{
-    fn into_string(&self) -> String {
-        format!(
-            "{})\n{}\n;;",
-            join!(self.patterns, "|"),
-            join!(self.body, "\n")
-        )
+impl<W: SerializeTo, C: SerializeTo> SerializeTo for ast::PatternBodyPair<W, C> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        join(out, &self.patterns, "|")?;
+        out.write_str(")\n")?;
+        join(out, &self.body, "\n")?;
+        out.write_str("\n;;")
     }
 }
 
-impl<T: Serializable<String>, R: Serializable<String>> Serializable<String>
-    for ast::CompoundCommand<T, R>
-{
-    fn into_string(&self) -> String {
-        let compound = self.kind.into_string();
+impl<T: SerializeTo, R: SerializeTo> SerializeTo for ast::CompoundCommand<T, R> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.kind.serialize_to(out)?;
 
-        let io = join!(self.io, " ");
+        // The following adds a space only when necessary
+        if !self.io.is_empty() {
+            out.write_char(' ')?;
+            join(out, &self.io, " ")?;
+        }
 
-        vec![compound, io]
-            .into_iter()
-            .filter(|x| x.len() != 0)
-            .collect::<Vec<String>>()
-            .join(" ")
+        Ok(())
     }
 }
 
-impl<L: Serializable<String>, W: Serializable<String>, R: Serializable<String>> Serializable<String>
-    for ast::SimpleCommand<L, W, R>
-{
-    fn into_string(&self) -> String {
-        let roev = join!(self.redirects_or_env_vars, " ");
-        let rocw = join!(self.redirects_or_cmd_words, " ");
+impl<L: SerializeTo, W: SerializeTo, R: SerializeTo> SerializeTo for ast::SimpleCommand<L, W, R> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        join(out, &self.redirects_or_env_vars, " ")?;
 
         // The following adds a space only when necessary
-        vec![roev, rocw]
-            .into_iter()
-            .filter(|x| x.len() != 0)
-            .collect::<Vec<String>>()
-            .join(" ")
+        if !self.redirects_or_env_vars.is_empty() && !self.redirects_or_cmd_words.is_empty() {
+            out.write_char(' ')?;
+        }
+
+        join(out, &self.redirects_or_cmd_words, " ")
     }
 }
 
-impl<T: Serializable<String>> Serializable<String> for ast::ListableCommand<T> {
-    fn into_string(&self) -> String {
+impl<T: SerializeTo> SerializeTo for ast::ListableCommand<T> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::ListableCommand::Single(cmd) => cmd.into_string(),
-            ast::ListableCommand::Pipe(_, cmds) => join!(cmds, " "),
+            ast::ListableCommand::Single(cmd) => cmd.serialize_to(out),
+            ast::ListableCommand::Pipe(false, cmds) => join(out, cmds, " | "),
+            ast::ListableCommand::Pipe(true, cmds) => {
+                out.write_str("! ")?;
+                join(out, cmds, " | ")
+            }
         }
     }
 }
 
-impl<
-        N: Serializable<String>,
-        S: Serializable<String>,
-        C: Serializable<String>,
-        F: Serializable<String>,
-    > Serializable<String> for ast::PipeableCommand<N, S, C, F>
+impl<N: SerializeTo, S: SerializeTo, C: SerializeTo, F: SerializeTo> SerializeTo
+    for ast::PipeableCommand<N, S, C, F>
 {
-    fn into_string(&self) -> String {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::PipeableCommand::Simple(cmd) => cmd.into_string(),
-            ast::PipeableCommand::Compound(cmd) => cmd.into_string(),
+            ast::PipeableCommand::Simple(cmd) => cmd.serialize_to(out),
+            ast::PipeableCommand::Compound(cmd) => cmd.serialize_to(out),
             ast::PipeableCommand::FunctionDef(name, body) => {
-                format!(
-                    "function {}() {{\n{}\n}}",
-                    name.into_string(),
-                    body.into_string()
-                )
+                name.serialize_to(out)?;
+                out.write_str("() ")?;
+                body.serialize_to(out)
             }
         }
     }
 }
 
-impl<T: Serializable<String>> Serializable<String> for ast::AndOrList<T> {
-    fn into_string(&self) -> String {
-        let first = self.first.into_string();
-        let rest = join!(self.rest, " ");
+impl<T: SerializeTo> SerializeTo for ast::AndOrList<T> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        self.first.serialize_to(out)?;
 
-        // The following adds a space only when necessary
-        vec![first, rest]
-            .into_iter()
-            .filter(|x| x.len() != 0)
-            .collect::<Vec<String>>()
-            .join(" ")
+        for and_or in self.rest.iter() {
+            out.write_char(' ')?;
+            and_or.serialize_to(out)?;
+        }
+
+        Ok(())
     }
 }
 
-impl<T: Serializable<String>> Serializable<String> for ast::Redirect<T> {
-    fn into_string(&self) -> String {
+// Serialize a heredoc redirection, with a delimiter that does not appear in the
+// body; bodies without expansions are quoted so that they are read verbatim
+fn heredoc<O: Output>(
+    out: &mut O,
+    fd: &Option<u16>,
+    body: &ast::TopLevelWord<String>,
+) -> fmt::Result {
+    let verbatim = match &body.0 {
+        ast::ComplexWord::Single(w) => vec![w],
+        ast::ComplexWord::Concat(v) => v.iter().collect(),
+    }
+    .iter()
+    .all(|w| matches!(w, ast::Word::Simple(ast::SimpleWord::Literal(_))));
+
+    // The body is looked through first, then written after the current line
+    let mut lines = Delimiters::default();
+    body.serialize_to(&mut lines)?;
+    lines.end_line();
+    let delimiter = lines.delimiter();
+
+    if let Some(fd) = fd {
+        write!(out, "{}", fd)?;
+    }
+
+    if verbatim {
+        write!(out, "<<'{}'", delimiter)?;
+    } else {
+        write!(out, "<<{}", delimiter)?;
+    }
+
+    let deferred = out.deferred()?;
+    let mut placed = Heredocs::new(deferred);
+    body.serialize_to(&mut placed)?;
+    placed.complete()?;
+
+    if lines.last.is_some_and(|c| c != '\n') {
+        deferred.write_char('\n')?;
+    }
+    writeln!(deferred, "{}", delimiter)
+}
+
+// Writer looking through a heredoc body for the lines that would end it early,
+// among EOF, EOF_1, EOF_2...
+#[derive(Default)]
+struct Delimiters {
+    // The current line, as long as it may be a delimiter
+    line: Option<String>,
+    // The indices of the delimiters found, EOF being 0
+    taken: Vec<usize>,
+    last: Option<char>,
+}
+
+impl Delimiters {
+    fn end_line(&mut self) {
+        let index = match self.line.take().as_deref() {
+            Some("EOF") => Some(0),
+            Some(line) => line
+                .strip_prefix("EOF_")
+                .and_then(|index| index.parse::<usize>().ok())
+                .filter(|index| line == format!("EOF_{}", index)),
+            None => None,
+        };
+
+        self.taken.extend(index);
+    }
+
+    fn delimiter(&self) -> String {
+        let mut index = 0;
+        while self.taken.contains(&index) {
+            index += 1;
+        }
+
+        match index {
+            0 => String::from("EOF"),
+            index => format!("EOF_{}", index),
+        }
+    }
+}
+
+impl Write for Delimiters {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.last.is_none_or(|last| last == '\n') {
+                self.line = Some(String::new());
+            }
+            self.last = Some(c);
+
+            if c == '\n' {
+                self.end_line();
+                continue;
+            }
+
+            if let Some(line) = self.line.as_mut() {
+                line.push(c);
+                if !"EOF_".starts_with(line.as_str())
+                    && !line
+                        .strip_prefix("EOF_")
+                        .is_some_and(|index| index.chars().all(|c| c.is_ascii_digit()))
+                {
+                    self.line = None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Output for Delimiters {
+    fn deferred(&mut self) -> Result<&mut dyn Write, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn write_nested(&mut self, s: &str) -> fmt::Result {
+        self.write_str(s)
+    }
+
+    fn complete(&mut self) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl SerializeTo for ast::DefaultRedirect {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
+        let (fd, operator, word) = match self {
+            ast::Redirect::Read(fd, word) => (fd, "< ", word),
+            ast::Redirect::Write(fd, word) => (fd, "> ", word),
+            ast::Redirect::ReadWrite(fd, word) => (fd, "<> ", word),
+            ast::Redirect::Append(fd, word) => (fd, ">> ", word),
+            ast::Redirect::Clobber(fd, word) => (fd, ">| ", word),
+            ast::Redirect::DupRead(fd, word) => (fd, "<&", word),
+            ast::Redirect::DupWrite(fd, word) => (fd, ">&", word),
+            ast::Redirect::Heredoc(fd, body) => return heredoc(out, fd, body),
+        };
+
+        if let Some(fd) = fd {
+            write!(out, "{}", fd)?;
+        }
+        out.write_str(operator)?;
+        word.serialize_to(out)
+    }
+}
+
+impl<R: SerializeTo, W: SerializeTo> SerializeTo for ast::RedirectOrCmdWord<R, W> {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::Redirect::Read(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}< {}", value, rest.into_string()),
-                None => format!("< {}", rest.into_string()),
-            },
-            ast::Redirect::Write(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}> {}", value, rest.into_string()),
-                None => format!("> {}", rest.into_string()),
-            },
-            ast::Redirect::ReadWrite(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}<> {}", value, rest.into_string()),
-                None => format!("<> {}", rest.into_string()),
-            },
-            ast::Redirect::Append(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}>> {}", value, rest.into_string()),
-                None => format!(">> {}", rest.into_string()),
-            },
-            ast::Redirect::Clobber(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}>| {}", value, rest.into_string()),
-                None => format!(">| {}", rest.into_string()),
-            },
-            ast::Redirect::DupRead(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}<& {}", value, rest.into_string()),
-                None => format!("<& {}", rest.into_string()),
-            },
-            ast::Redirect::DupWrite(fd_option, rest) => match fd_option {
-                Some(value) => format!("{}>& {}", value, rest.into_string()),
-                None => format!(">& {}", rest.into_string()),
-            },
-            ast::Redirect::Heredoc(fd_option, rest) => match fd_option {
-                Some(value) => format!("{} in {}", rest.into_string(), value),
-                None => format!("<<EOF\n{}\nEOF", rest.into_string()),
-            },
-        }
-    }
-}
-
-impl<R: Serializable<String>, W: Serializable<String>> Serializable<String>
-    for ast::RedirectOrCmdWord<R, W>
+            ast::RedirectOrCmdWord::CmdWord(w) => w.serialize_to(out),
+            ast::RedirectOrCmdWord::Redirect(r) => r.serialize_to(out),
+        }
+    }
+}
+
+impl<R: SerializeTo, V: SerializeTo, W: SerializeTo> SerializeTo
+    for ast::RedirectOrEnvVar<R, V, W>
 {
-    fn into_string(&self) -> String {
+    fn serialize_to<O: Output>(&self, out: &mut O) -> fmt::Result {
         match self {
-            ast::RedirectOrCmdWord::CmdWord(w) => w.into_string(),
-            ast::RedirectOrCmdWord::Redirect(r) => r.into_string(),
+            ast::RedirectOrEnvVar::EnvVar(k, v) => {
+                k.serialize_to(out)?;
+                out.write_char('=')?;
+                match v {
+                    Some(v) => v.serialize_to(out),
+                    None => Ok(()),
+                }
+            }
+            ast::RedirectOrEnvVar::Redirect(r) => r.serialize_to(out),
         }
     }
 }
 
-impl<R: Serializable<String>, V: Serializable<String>, W: Serializable<String>> Serializable<String>
-    for ast::RedirectOrEnvVar<R, V, W>
+// Indentation of nested bodies when pretty-printing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs,
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(4)
+    }
+}
+
+impl Indent {
+    fn write_at<O: Output>(&self, out: &mut O, level: usize) -> fmt::Result {
+        let (unit, count) = match self {
+            Indent::Spaces(width) => (' ', width * level),
+            Indent::Tabs => ('\t', level),
+        };
+
+        for _ in 0..count {
+            out.write_char(unit)?;
+        }
+
+        Ok(())
+    }
+
+    // Pretty-print a command, with its heredoc bodies following the lines they
+    // appear on
+    pub fn format_to<O: Write>(
+        &self,
+        out: &mut O,
+        cmd: &ast::TopLevelCommand<String>,
+    ) -> fmt::Result {
+        let mut placed = Heredocs::new(out);
+        cmd.pretty_to(&mut placed, *self, 0)?;
+        placed.complete()
+    }
+
+    pub fn format(&self, cmd: &ast::TopLevelCommand<String>) -> String {
+        let mut string = String::new();
+        self.format_to(&mut string, cmd)
+            .expect("a String cannot fail to be written");
+        string
+    }
+}
+
+// Serialization laying out compound commands over indented lines; guards and
+// command substitutions are kept on a single line
+pub trait Pretty {
+    // Bodies are indented one level deeper than the command, whose first line is
+    // indented by the caller
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result;
+}
+
+impl<B: Pretty> Pretty for Box<B> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        (**self).pretty_to(out, indent, level)
+    }
+}
+
+impl<B: Pretty> Pretty for Rc<B> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        (**self).pretty_to(out, indent, level)
+    }
+}
+
+// Heredoc bodies are left pending until the outermost command is complete
+impl Pretty for ast::TopLevelCommand<String> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        self.0.pretty_to(out, indent, level)
+    }
+}
+
+impl<T: Pretty> Pretty for ast::Command<T> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        match self {
+            ast::Command::Job(list) => {
+                list.pretty_to(out, indent, level)?;
+                out.write_str(" &")
+            }
+            ast::Command::List(list) => list.pretty_to(out, indent, level),
+        }
+    }
+}
+
+impl<T: Pretty> Pretty for ast::AndOrList<T> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        self.first.pretty_to(out, indent, level)?;
+
+        for and_or in self.rest.iter() {
+            match and_or {
+                ast::AndOr::And(cmd) => {
+                    out.write_str(" && ")?;
+                    cmd.pretty_to(out, indent, level)?;
+                }
+                ast::AndOr::Or(cmd) => {
+                    out.write_str(" || ")?;
+                    cmd.pretty_to(out, indent, level)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Pretty> Pretty for ast::ListableCommand<T> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        match self {
+            ast::ListableCommand::Single(cmd) => cmd.pretty_to(out, indent, level),
+            ast::ListableCommand::Pipe(bang, cmds) => {
+                if *bang {
+                    out.write_str("! ")?;
+                }
+
+                for (index, cmd) in cmds.iter().enumerate() {
+                    if index > 0 {
+                        out.write_str(" | ")?;
+                    }
+                    cmd.pretty_to(out, indent, level)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<N: SerializeTo, S: SerializeTo, C: Pretty, F: Pretty> Pretty
+    for ast::PipeableCommand<N, S, C, F>
 {
-    fn into_string(&self) -> String {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        match self {
+            ast::PipeableCommand::Simple(cmd) => cmd.serialize_to(out),
+            ast::PipeableCommand::Compound(cmd) => cmd.pretty_to(out, indent, level),
+            ast::PipeableCommand::FunctionDef(name, body) => {
+                name.serialize_to(out)?;
+                out.write_str("() ")?;
+                body.pretty_to(out, indent, level)
+            }
+        }
+    }
+}
+
+impl<T: Pretty, R: SerializeTo> Pretty for ast::CompoundCommand<T, R> {
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        self.kind.pretty_to(out, indent, level)?;
+
+        if !self.io.is_empty() {
+            out.write_char(' ')?;
+            join(out, &self.io, " ")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Lay out a sequence of commands one per line, indented at the given level
+fn block<O: Output, C: Pretty>(
+    out: &mut O,
+    cmds: &[C],
+    indent: Indent,
+    level: usize,
+) -> fmt::Result {
+    for cmd in cmds.iter() {
+        out.write_char('\n')?;
+        indent.write_at(out, level)?;
+        cmd.pretty_to(out, indent, level)?;
+    }
+
+    Ok(())
+}
+
+impl<V: SerializeTo, W: SerializeTo, C: SerializeTo + Pretty> Pretty
+    for ast::CompoundCommandKind<V, W, C>
+{
+    fn pretty_to<O: Output>(&self, out: &mut O, indent: Indent, level: usize) -> fmt::Result {
+        // Write the keyword closing a compound command on its own line
+        let close = |out: &mut O, keyword: &str| {
+            out.write_char('\n')?;
+            indent.write_at(out, level)?;
+            out.write_str(keyword)
+        };
+
         match self {
-            ast::RedirectOrEnvVar::EnvVar(k, Some(v)) => {
-                format!("{}={}", k.into_string(), v.into_string())
+            ast::CompoundCommandKind::Brace(vec) => {
+                out.write_char('{')?;
+                block(out, vec, indent, level + 1)?;
+                close(out, "}")
+            }
+            ast::CompoundCommandKind::Subshell(vec) => {
+                out.write_char('(')?;
+                block(out, vec, indent, level + 1)?;
+                close(out, ")")
+            }
+            ast::CompoundCommandKind::While(gbp) => {
+                out.write_str("while ")?;
+                separate(out, &gbp.guard, true)?;
+                out.write_str(" do")?;
+                block(out, &gbp.body, indent, level + 1)?;
+                close(out, "done")
+            }
+            ast::CompoundCommandKind::Until(gbp) => {
+                out.write_str("until ")?;
+                separate(out, &gbp.guard, true)?;
+                out.write_str(" do")?;
+                block(out, &gbp.body, indent, level + 1)?;
+                close(out, "done")
+            }
+            ast::CompoundCommandKind::If {
+                conditionals,
+                else_branch,
+            } => {
+                for (index, gbp) in conditionals.iter().enumerate() {
+                    if index > 0 {
+                        close(out, "el")?;
+                    }
+                    out.write_str("if ")?;
+                    separate(out, &gbp.guard, true)?;
+                    out.write_str(" then")?;
+                    block(out, &gbp.body, indent, level + 1)?;
+                }
+
+                if let Some(statements) = else_branch {
+                    close(out, "else")?;
+                    block(out, statements, indent, level + 1)?;
+                }
+
+                close(out, "fi")
+            }
+            ast::CompoundCommandKind::For { var, words, body } => {
+                out.write_str("for ")?;
+                var.serialize_to(out)?;
+                if let Some(words) = words {
+                    out.write_str(" in ")?;
+                    join(out, words, " ")?;
+                }
+                out.write_str("; do")?;
+                block(out, body, indent, level + 1)?;
+                close(out, "done")
+            }
+            ast::CompoundCommandKind::Case { word, arms } => {
+                out.write_str("case ")?;
+                word.serialize_to(out)?;
+                out.write_str(" in")?;
+
+                for arm in arms.iter() {
+                    out.write_char('\n')?;
+                    indent.write_at(out, level + 1)?;
+                    join(out, &arm.patterns, "|")?;
+                    out.write_char(')')?;
+                    block(out, &arm.body, indent, level + 2)?;
+                    out.write_char('\n')?;
+                    indent.write_at(out, level + 2)?;
+                    out.write_str(";;")?;
+                }
+
+                close(out, "esac")
             }
-            ast::RedirectOrEnvVar::EnvVar(k, None) => format!("{}=", k.into_string()),
-            ast::RedirectOrEnvVar::Redirect(r) => r.into_string(),
         }
     }
 }
@@ -511,6 +1224,21 @@ impl<R: Serializable<String>, V: Serializable<String>, W: Serializable<String>>
 #[cfg(test)]
 mod test {
     use super::*;
+    use conch_parser::lexer::Lexer;
+    use conch_parser::parse::DefaultParser;
+
+    fn parse(script: &str) -> Vec<ast::TopLevelCommand<String>> {
+        DefaultParser::new(Lexer::new(script.chars()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn serialize(script: &str) -> String {
+        let mut serialized = String::new();
+        join(&mut Heredocs::new(&mut serialized), &parse(script), "\n").unwrap();
+        serialized
+    }
 
     #[test]
     fn test_serialize_string() {
@@ -823,4 +1551,136 @@ mod test {
             String::from("1, 2, 3")
         );
     }
+
+    #[test]
+    fn test_serialize_pipeline() {
+        assert_eq!(
+            serialize("module avail 2>&1 | grep gcc"),
+            "module avail 2>&1 | grep gcc"
+        );
+        assert_eq!(serialize("! a | b | c"), "! a | b | c");
+        assert_eq!(serialize("! true"), "! true");
+        assert_eq!(serialize("x=$( (a) | b) y=$()"), "x=$( ( a ) | b) y=$()");
+        assert_eq!(serialize("a | b && ! c | d || e"), "a | b && ! c | d || e");
+
+        // Pipelines must parse back to the same tree
+        for script in [
+            "module avail 2>&1 | grep gcc",
+            "! spack find | grep -q zlib",
+            "if ! a | b; then c | d; fi",
+            "x=$(ls | wc -l)",
+        ] {
+            assert_eq!(parse(&serialize(script)), parse(script));
+        }
+    }
+
+    #[test]
+    fn test_serialize_quoting() {
+        assert_eq!(serialize("echo 'a  $b'"), "echo 'a  $b'");
+        assert_eq!(serialize("echo a'b c'd"), "echo a'b c'd");
+        assert_eq!(
+            serialize(r#"echo "a\"b\$c\\d\e""#),
+            r#"echo "a\"b\$c\\d\e""#
+        );
+        assert_eq!(serialize(r"echo a\ b \*"), r"echo a\ b \*");
+        assert_eq!(
+            serialize(r#"echo "${x}abc" ${y}_ $z/"#),
+            r#"echo "${x}abc" ${y}_ $z/"#
+        );
+        assert_eq!(serialize("echo ${10}"), "echo ${10}");
+
+        // Quoted words must parse back to the same tree
+        for script in [
+            "echo 'a  $b' \"it's\" ''",
+            r#"echo "a\"b\$c\\d\e`x`" "$(echo "nested $x")""#,
+            r"echo a\ b \' \$HOME \\",
+            r#"echo "${x}abc" ${y}_ pre${z}post "$1"0 ${10}"#,
+            r#"printf '%s\n' "a	b" x"$y"'$z'"#,
+        ] {
+            assert_eq!(parse(&serialize(script)), parse(script));
+        }
+    }
+
+    #[test]
+    fn test_serialize_heredoc() {
+        assert_eq!(
+            serialize("cat <<END\nhello $USER\nEND"),
+            "cat <<EOF\nhello $USER\nEOF\n"
+        );
+        assert_eq!(
+            serialize("cat <<'END'\nhello $USER\nEND"),
+            "cat <<'EOF'\nhello $USER\nEOF\n"
+        );
+        assert_eq!(
+            serialize("cat 3<<-END\n\tEOF\n\tEND"),
+            "cat 3<<'EOF_1'\nEOF\nEOF_1\n"
+        );
+        assert_eq!(
+            serialize("cat <<END\nEOF_1\nEOF\nEOF_01\nEND"),
+            "cat <<'EOF_2'\nEOF_1\nEOF\nEOF_01\nEOF_2\n"
+        );
+        assert_eq!(
+            serialize("cat <<A | sed -e s/a/b/ <<'B' && wc\na\nA\nb\nB"),
+            "cat <<'EOF' | sed -e s/a/b/ <<'EOF' && wc\na\nEOF\nb\nEOF\n"
+        );
+
+        // Bodies are read on the line following the command
+        for script in [
+            "cat <<EOF > file\n$HOME \\$PATH `pwd`\nEOF\necho done",
+            "if true; then cat <<EOF\nbody\nEOF\nfi",
+            "while read line; do echo $line; done <<EOF\na\nb\nEOF",
+            "x=$(cat <<EOF\nnested\nEOF\n)",
+            "{ cat <<A; cat <<B; }\na\nA\nb\nB",
+        ] {
+            assert_eq!(parse(&serialize(script)), parse(script));
+        }
+    }
+
+    #[test]
+    fn test_pretty() {
+        let pretty = |script: &str, indent: Indent| {
+            parse(script)
+                .iter()
+                .map(|cmd| indent.format(cmd))
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+
+        assert_eq!(
+            pretty(
+                "if true; then for x in a b; do echo $x; done; elif false; then :; else { a; b; }; fi",
+                Indent::default()
+            ),
+            "if true; then\n    for x in a b; do\n        echo $x\n    done\nelif false; then\n    :\nelse\n    {\n        a\n        b\n    }\nfi"
+        );
+        assert_eq!(
+            pretty("f() { case $1 in a|b) echo a;; *) ;; esac; }", Indent::Tabs),
+            "f() {\n\tcase $1 in\n\t\ta|b)\n\t\t\techo a\n\t\t\t;;\n\t\t*)\n\t\t\t;;\n\tesac\n}"
+        );
+        assert_eq!(
+            pretty(
+                "while read x; do cat <<EOF; done < file\n$x\nEOF",
+                Indent::Spaces(2)
+            ),
+            "while read x; do\n  cat <<EOF\n$x\nEOF\ndone < file"
+        );
+    }
+
+    #[test]
+    fn test_serialize_io() {
+        let cmd = &parse("cat <<EOF | while read x; do echo $x; done\nbody\nEOF")[0];
+
+        let mut bytes = vec![];
+        cmd.serialize_io(&mut bytes).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), cmd.into_string());
+
+        let mut text = String::new();
+        cmd.serialize_fmt(&mut text as &mut dyn Write).unwrap();
+        assert_eq!(text, cmd.into_string());
+
+        // Errors of the sink are reported as is
+        let mut full = [0u8; 4];
+        let error = cmd.serialize_io(&mut full.as_mut_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
 }
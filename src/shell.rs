@@ -0,0 +1,847 @@
+use crate::environment::{is_path_list, Modification};
+use crate::serializable::Serializable;
+use crate::transform::literal;
+use crate::Error;
+use conch_parser::ast;
+use std::fmt;
+use std::str::FromStr;
+
+// The shells unspacked scripts can be generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Shell {
+    #[default]
+    Bash,
+    Sh,
+    Csh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "sh" => Ok(Shell::Sh),
+            "csh" | "tcsh" => Ok(Shell::Csh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(Error::Unsupported(format!("Unknown shell {}", name))),
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Shell::Bash => "bash",
+            Shell::Sh => "sh",
+            Shell::Csh => "csh",
+            Shell::Fish => "fish",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Shell {
+    // The flags selecting the syntax of spack's shell output
+    pub const SPACK_FLAGS: [&'static str; 3] = ["--sh", "--csh", "--fish"];
+
+    pub fn spack_flag(&self) -> &'static str {
+        match self {
+            Shell::Bash | Shell::Sh => "--sh",
+            Shell::Csh => "--csh",
+            Shell::Fish => "--fish",
+        }
+    }
+
+    // Return true if the shell accepts the syntax of the input scripts
+    pub fn is_posix(&self) -> bool {
+        matches!(self, Shell::Bash | Shell::Sh)
+    }
+
+    pub fn shebang(&self) -> &'static str {
+        match self {
+            Shell::Bash => "#!/bin/bash",
+            Shell::Sh => "#!/bin/sh",
+            Shell::Csh => "#!/bin/csh",
+            Shell::Fish => "#!/usr/bin/env fish",
+        }
+    }
+
+    // Define a function running the given commands; csh has no functions, so an
+    // alias is defined instead
+    pub fn function(&self, name: &str, comment: &str, body: &[String]) -> String {
+        match self {
+            Shell::Bash | Shell::Sh => {
+                format!("{}() {{\n# {}\n{}\n}}\n", name, comment, body.join("\n"))
+            }
+            Shell::Fish => format!(
+                "function {}\n# {}\n{}\nend\n",
+                name,
+                comment,
+                body.join("\n")
+            ),
+            Shell::Csh => format!(
+                "# {}\nalias {} '{}'\n",
+                comment,
+                name,
+                body.join("; ").replace('\'', "'\\''")
+            ),
+        }
+    }
+
+    // Prepend entries to a colon-separated variable, keeping its current value
+    pub fn prepend(&self, variable: &str, entries: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Sh => format!(
+                "export {}={}${{{}:+:${}}}",
+                variable, entries, variable, variable
+            ),
+            // An empty variable is defined, and would leave a leading colon
+            Shell::Fish => format!(
+                "test -n \"${}\"; and set -gx {} \"{}:${}\"; or set -gx {} \"{}\"",
+                variable, variable, entries, variable, variable, entries
+            ),
+            // Undefined variables are errors in csh, even in branches not taken
+            Shell::Csh => format!(
+                "setenv {} \"{}`printenv {} | sed -e s/^/:/`\"",
+                variable, entries, variable
+            ),
+        }
+    }
+
+    // Append entries to a colon-separated variable, keeping its current value
+    pub fn append(&self, variable: &str, entries: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Sh => format!(
+                "export {}=${{{}:+${}:}}{}",
+                variable, variable, variable, entries
+            ),
+            Shell::Fish => format!(
+                "test -n \"${}\"; and set -gx {} \"${}:{}\"; or set -gx {} \"{}\"",
+                variable, variable, variable, entries, variable, entries
+            ),
+            Shell::Csh => format!(
+                "setenv {} \"`printenv {} | sed -e 's/.*/&:/'`{}\"",
+                variable, variable, entries
+            ),
+        }
+    }
+
+    // Remove entries from a colon-separated variable
+    pub fn remove(&self, variable: &str, entries: &[String]) -> String {
+        let filter = format!(
+            "tr : '\\n' | grep -vxF{} | paste -sd: -",
+            entries
+                .iter()
+                .map(|e| format!(" -e {}", self.quote(e)))
+                .collect::<String>()
+        );
+
+        match self {
+            Shell::Bash | Shell::Sh => format!(
+                "export {}=\"$(printf '%s\\n' \"${}\" | {})\"",
+                variable, variable, filter
+            ),
+            Shell::Fish => format!("set -gx {} (printenv {} | {})", variable, variable, filter),
+            Shell::Csh => format!(
+                "setenv {} \"`printenv {} | {}`\"",
+                variable, variable, filter
+            ),
+        }
+    }
+
+    // Quote a value so that it is taken literally
+    pub fn quote(&self, value: &str) -> String {
+        match self {
+            Shell::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+            _ => format!("'{}'", value.replace('\'', "'\\''")),
+        }
+    }
+
+    // Apply a modification to an environment variable
+    pub fn modify(&self, variable: &str, modification: &Modification) -> String {
+        match (self, modification) {
+            (Shell::Bash | Shell::Sh, Modification::Set(value)) => {
+                format!("export {}={}", variable, self.quote(value))
+            }
+            (Shell::Fish, Modification::Set(value)) => {
+                format!("set -gx {} {}", variable, self.quote(value))
+            }
+            (Shell::Csh, Modification::Set(value)) => {
+                format!("setenv {} {}", variable, self.quote(value))
+            }
+            (Shell::Bash | Shell::Sh, Modification::Unset) => format!("unset {}", variable),
+            (Shell::Fish, Modification::Unset) => format!("set -e {}", variable),
+            (Shell::Csh, Modification::Unset) => format!("unsetenv {}", variable),
+            (_, Modification::Prepend(entries)) => self.prepend(variable, &entries.join(":")),
+            (_, Modification::Append(entries)) => self.append(variable, &entries.join(":")),
+            (_, Modification::Remove(entries)) => self.remove(variable, entries),
+            (_, Modification::Add(entries)) => format!(
+                "{}; {}",
+                self.remove(variable, entries),
+                self.append(variable, &entries.join(":"))
+            ),
+        }
+    }
+}
+
+fn unsupported(what: &str, shell: Shell) -> Error {
+    Error::Unsupported(format!("{} cannot be translated to {}", what, shell))
+}
+
+// Serialization of a parsed script in the syntax of another shell
+pub trait Translatable {
+    fn translate(&self, shell: Shell) -> Result<String, Error>;
+}
+
+impl<T: Translatable> Translatable for Box<T> {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        (**self).translate(shell)
+    }
+}
+
+impl<T: Translatable> Translatable for &T {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        (**self).translate(shell)
+    }
+}
+
+fn translate_all<T: Translatable>(
+    items: &[T],
+    shell: Shell,
+    separator: &str,
+) -> Result<String, Error> {
+    Ok(items
+        .iter()
+        .map(|item| item.translate(shell))
+        .collect::<Result<Vec<String>, Error>>()?
+        .join(separator))
+}
+
+impl Translatable for ast::TopLevelCommand<String> {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        if shell.is_posix() {
+            return Ok(self.into_string());
+        }
+
+        match &self.0 {
+            ast::Command::Job(list) => Ok(format!("{} &", list.translate(shell)?)),
+            ast::Command::List(list) => list.translate(shell),
+        }
+    }
+}
+
+impl Translatable for ast::DefaultAndOrList {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        let mut list = vec![self.first.translate(shell)?];
+
+        for and_or in self.rest.iter() {
+            list.push(match and_or {
+                ast::AndOr::And(cmd) => format!("&& {}", cmd.translate(shell)?),
+                ast::AndOr::Or(cmd) => format!("|| {}", cmd.translate(shell)?),
+            });
+        }
+
+        Ok(list.join(" "))
+    }
+}
+
+impl Translatable for ast::DefaultListableCommand {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        match self {
+            ast::ListableCommand::Single(cmd) => cmd.translate(shell),
+            ast::ListableCommand::Pipe(false, cmds) => translate_all(cmds, shell, " | "),
+            ast::ListableCommand::Pipe(true, cmds) => match shell {
+                Shell::Csh => Err(unsupported("Pipeline negation", shell)),
+                _ => Ok(format!("not {}", translate_all(cmds, shell, " | ")?)),
+            },
+        }
+    }
+}
+
+impl Translatable for ast::DefaultPipeableCommand {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        match self {
+            ast::PipeableCommand::Simple(cmd) => cmd.translate(shell),
+            ast::PipeableCommand::Compound(cmd) => cmd.translate(shell),
+            ast::PipeableCommand::FunctionDef(name, body) => match (shell, &body.kind) {
+                (Shell::Fish, ast::CompoundCommandKind::Brace(cmds)) if body.io.is_empty() => {
+                    Ok(format!(
+                        "function {}\n{}\nend",
+                        name,
+                        translate_all(cmds, shell, "\n")?
+                    ))
+                }
+                (Shell::Fish, _) => Ok(format!(
+                    "function {}\n{}\nend",
+                    name,
+                    body.translate(shell)?
+                )),
+                _ => Err(unsupported(&format!("Function {}", name), shell)),
+            },
+        }
+    }
+}
+
+// Split an assignment such as NAME=value, once translated
+fn assignment(
+    word: &ast::TopLevelWord<String>,
+    shell: Shell,
+) -> Result<(String, Option<String>), Error> {
+    let translated = word.translate(shell)?;
+
+    Ok(match translated.split_once('=') {
+        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+        None => (translated, None),
+    })
+}
+
+// Translate a word to fish as a list of the parts between its unquoted colons;
+// fish holds variables ending in PATH as lists, which would repeat a value
+// concatenated to them once for each of their entries
+fn fish_list(word: &ast::TopLevelWord<String>) -> Result<String, Error> {
+    let words = match &word.0 {
+        ast::ComplexWord::Single(w) => vec![w.clone()],
+        ast::ComplexWord::Concat(v) => v.clone(),
+    };
+
+    words
+        .split(|w| matches!(w, ast::Word::Simple(ast::SimpleWord::Colon)))
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            ast::TopLevelWord(ast::ComplexWord::Concat(part.to_vec())).translate(Shell::Fish)
+        })
+        .collect::<Result<Vec<String>, Error>>()
+        .map(|parts| parts.join(" "))
+}
+
+impl Translatable for ast::DefaultSimpleCommand {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        let mut assignments = vec![];
+        let mut words = vec![];
+        let mut redirects = vec![];
+
+        for roev in self.redirects_or_env_vars.iter() {
+            match roev {
+                ast::RedirectOrEnvVar::EnvVar(name, value) => {
+                    let value = match value {
+                        Some(value) => value.translate(shell)?,
+                        None => String::new(),
+                    };
+                    assignments.push((name.clone(), value));
+                }
+                ast::RedirectOrEnvVar::Redirect(r) => redirects.push(r.translate(shell)?),
+            }
+        }
+
+        for rocw in self.redirects_or_cmd_words.iter() {
+            match rocw {
+                ast::RedirectOrCmdWord::CmdWord(w) => words.push(w),
+                ast::RedirectOrCmdWord::Redirect(r) => redirects.push(r.translate(shell)?),
+            }
+        }
+
+        // Plain assignments set shell variables
+        if words.is_empty() {
+            if !redirects.is_empty() {
+                return Err(unsupported("Redirection of assignments", shell));
+            }
+
+            return Ok(assignments
+                .iter()
+                .map(|(name, value)| match shell {
+                    Shell::Csh => format!("set {}={}", name, value),
+                    _ => format!("set {} {}", name, value),
+                })
+                .collect::<Vec<String>>()
+                .join("; "));
+        }
+
+        let arguments = &words[1..];
+
+        let mut command = match literal(words[0]).as_deref() {
+            Some("export") => {
+                let exports = arguments
+                    .iter()
+                    .map(|word| {
+                        let (name, mut value) = assignment(word, shell)?;
+                        if shell == Shell::Fish && value.is_some() && is_path_list(&name) {
+                            value = fish_list(word)?
+                                .split_once('=')
+                                .map(|(_, value)| value.to_string());
+                        }
+
+                        let value = value.unwrap_or(match shell {
+                            Shell::Csh => format!("\"${{{}}}\"", name),
+                            _ => format!("${}", name),
+                        });
+
+                        Ok(match shell {
+                            Shell::Csh => format!("setenv {} {}", name, value),
+                            _ => format!("set -gx {} {}", name, value),
+                        })
+                    })
+                    .collect::<Result<Vec<String>, Error>>()?;
+
+                exports.join("; ")
+            }
+            Some("unset") => {
+                let names = translate_all(arguments, shell, " ")?;
+                match shell {
+                    Shell::Csh => format!("unset {}; unsetenv {}", names, names),
+                    _ => format!("set -e {}", names),
+                }
+            }
+            // Aliases are defined as alias name value in csh
+            Some("alias") if shell == Shell::Csh => arguments
+                .iter()
+                .map(|word| match assignment(word, shell)? {
+                    (name, Some(value)) => Ok(format!("alias {} {}", name, value)),
+                    (name, None) => Ok(format!("alias {}", name)),
+                })
+                .collect::<Result<Vec<String>, Error>>()?
+                .join("; "),
+            Some(".") => format!("source {}", translate_all(arguments, shell, " ")?),
+            _ => {
+                let words = words
+                    .iter()
+                    .map(|w| w.translate(shell))
+                    .collect::<Result<Vec<String>, Error>>()?;
+                words.join(" ")
+            }
+        };
+
+        // Assignments preceding a command only apply to it
+        if !assignments.is_empty() {
+            let assignments = assignments
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>();
+            command = format!("env {} {}", assignments.join(" "), command);
+        }
+
+        redirects.insert(0, command);
+        Ok(redirects.join(" "))
+    }
+}
+
+// Format the guard of a conditional or loop for the given shell
+fn guard(cmds: &[ast::TopLevelCommand<String>], shell: Shell) -> Result<String, Error> {
+    match (shell, cmds) {
+        (Shell::Csh, [cmd]) => Ok(format!("{{ {} }}", cmd.translate(shell)?)),
+        (Shell::Csh, _) => Err(unsupported("Compound condition", shell)),
+        (_, [cmd]) => cmd.translate(shell),
+        _ => Ok(format!("begin; {}; end", translate_all(cmds, shell, "; ")?)),
+    }
+}
+
+impl Translatable for ast::DefaultCompoundCommand {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        if shell == Shell::Csh && !self.io.is_empty() {
+            return Err(unsupported("Redirection of compound commands", shell));
+        }
+
+        let compound = match (shell, &self.kind) {
+            (Shell::Fish, ast::CompoundCommandKind::Brace(cmds)) => {
+                format!("begin\n{}\nend", translate_all(cmds, shell, "\n")?)
+            }
+            (Shell::Csh, ast::CompoundCommandKind::Brace(cmds)) => {
+                translate_all(cmds, shell, "\n")?
+            }
+            (Shell::Csh, ast::CompoundCommandKind::Subshell(cmds)) => {
+                format!("( {} )", translate_all(cmds, shell, "; ")?)
+            }
+            (Shell::Fish, ast::CompoundCommandKind::While(gbp)) => format!(
+                "while {}\n{}\nend",
+                guard(&gbp.guard, shell)?,
+                translate_all(&gbp.body, shell, "\n")?
+            ),
+            (Shell::Fish, ast::CompoundCommandKind::Until(gbp)) => format!(
+                "while not {}\n{}\nend",
+                guard(&gbp.guard, shell)?,
+                translate_all(&gbp.body, shell, "\n")?
+            ),
+            (Shell::Csh, ast::CompoundCommandKind::While(gbp)) => format!(
+                "while ( {} )\n{}\nend",
+                guard(&gbp.guard, shell)?,
+                translate_all(&gbp.body, shell, "\n")?
+            ),
+            (Shell::Csh, ast::CompoundCommandKind::Until(gbp)) => format!(
+                "while ( ! {} )\n{}\nend",
+                guard(&gbp.guard, shell)?,
+                translate_all(&gbp.body, shell, "\n")?
+            ),
+            (
+                _,
+                ast::CompoundCommandKind::If {
+                    conditionals,
+                    else_branch,
+                },
+            ) => {
+                let mut branches = vec![];
+
+                for gbp in conditionals.iter() {
+                    let condition = match shell {
+                        Shell::Csh => format!("if ( {} ) then", guard(&gbp.guard, shell)?),
+                        _ => format!("if {}", guard(&gbp.guard, shell)?),
+                    };
+                    branches.push(format!(
+                        "{}\n{}",
+                        condition,
+                        translate_all(&gbp.body, shell, "\n")?
+                    ));
+                }
+
+                let mut statement = branches.join("\nelse ");
+
+                if let Some(cmds) = else_branch {
+                    statement =
+                        format!("{}\nelse\n{}", statement, translate_all(cmds, shell, "\n")?);
+                }
+
+                match shell {
+                    Shell::Csh => format!("{}\nendif", statement),
+                    _ => format!("{}\nend", statement),
+                }
+            }
+            (_, ast::CompoundCommandKind::For { var, words, body }) => {
+                let words = match words {
+                    Some(words) => translate_all(words, shell, " ")?,
+                    None => String::from("$argv"),
+                };
+
+                match shell {
+                    Shell::Csh => format!(
+                        "foreach {} ( {} )\n{}\nend",
+                        var,
+                        words,
+                        translate_all(body, shell, "\n")?
+                    ),
+                    _ => format!(
+                        "for {} in {}\n{}\nend",
+                        var,
+                        words,
+                        translate_all(body, shell, "\n")?
+                    ),
+                }
+            }
+            (_, ast::CompoundCommandKind::Case { word, arms }) => {
+                let mut cases = vec![];
+
+                for arm in arms.iter() {
+                    let patterns = arm
+                        .patterns
+                        .iter()
+                        .map(|p| p.translate(shell))
+                        .collect::<Result<Vec<String>, Error>>()?;
+                    let body = translate_all(&arm.body, shell, "\n")?;
+
+                    cases.push(match shell {
+                        Shell::Csh => {
+                            let labels = patterns
+                                .iter()
+                                .map(|p| match p.as_str() {
+                                    "*" => String::from("default:"),
+                                    p => format!("case {}:", p),
+                                })
+                                .collect::<Vec<String>>();
+                            format!("{}\n{}\nbreaksw", labels.join("\n"), body)
+                        }
+                        // Patterns are globbed in fish unless quoted
+                        _ => {
+                            let patterns = patterns
+                                .iter()
+                                .map(|p| match p.contains(['$', '\'']) {
+                                    true => p.clone(),
+                                    false => format!("'{}'", p),
+                                })
+                                .collect::<Vec<String>>();
+                            format!("case {}\n{}", patterns.join(" "), body)
+                        }
+                    });
+                }
+
+                match shell {
+                    Shell::Csh => format!(
+                        "switch ( {} )\n{}\nendsw",
+                        word.translate(shell)?,
+                        cases.join("\n")
+                    ),
+                    _ => format!(
+                        "switch {}\n{}\nend",
+                        word.translate(shell)?,
+                        cases.join("\n")
+                    ),
+                }
+            }
+            (_, ast::CompoundCommandKind::Subshell(_)) => {
+                return Err(unsupported("Subshell", shell))
+            }
+            (_, kind) => return Ok(kind.into_string()),
+        };
+
+        let mut io = vec![compound];
+        io.append(
+            &mut self
+                .io
+                .iter()
+                .map(|r| r.translate(shell))
+                .collect::<Result<Vec<String>, Error>>()?,
+        );
+
+        Ok(io.join(" "))
+    }
+}
+
+impl Translatable for ast::DefaultRedirect {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        let (fd, symbol, word, default) = match self {
+            ast::Redirect::Read(fd, w) => (fd, "<", w, 0),
+            ast::Redirect::Write(fd, w) => (fd, ">", w, 1),
+            ast::Redirect::Append(fd, w) => (fd, ">>", w, 1),
+            ast::Redirect::Clobber(fd, w) => match shell {
+                Shell::Csh => (fd, ">!", w, 1),
+                _ => (fd, ">", w, 1),
+            },
+            ast::Redirect::DupRead(fd, w) if shell == Shell::Fish => (fd, "<&", w, 0),
+            ast::Redirect::DupWrite(fd, w) if shell == Shell::Fish => (fd, ">&", w, 1),
+            ast::Redirect::Heredoc(..) => return Err(unsupported("Heredoc", shell)),
+            _ => return Err(unsupported(&self.into_string(), shell)),
+        };
+
+        // Duplicated descriptors follow the operator directly
+        let target = match symbol.ends_with('&') {
+            true => word.translate(shell)?,
+            false => format!(" {}", word.translate(shell)?),
+        };
+
+        // csh can only redirect the standard streams
+        match (shell, fd) {
+            (Shell::Csh, Some(fd)) if *fd != default => {
+                Err(unsupported(&self.into_string(), shell))
+            }
+            (_, Some(fd)) if *fd != default => Ok(format!("{}{}{}", fd, symbol, target)),
+            _ => Ok(format!("{}{}", symbol, target)),
+        }
+    }
+}
+
+impl Translatable for ast::TopLevelWord<String> {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        let words = match &self.0 {
+            ast::ComplexWord::Single(w) => vec![w],
+            ast::ComplexWord::Concat(v) => v.iter().collect(),
+        };
+
+        let mut translated = String::new();
+
+        for (index, word) in words.iter().enumerate() {
+            match (shell, word) {
+                // Delimit variables from the text following them
+                (
+                    Shell::Fish,
+                    ast::Word::Simple(ast::SimpleWord::Param(ast::Parameter::Var(v))),
+                ) if index + 1 < words.len() => translated.push_str(&format!("{{${}}}", v)),
+                (_, word) => translated.push_str(&word.translate(shell)?),
+            }
+        }
+
+        Ok(translated)
+    }
+}
+
+impl Translatable for ast::DefaultWord {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        match self {
+            ast::Word::Simple(w) => w.translate(shell),
+            ast::Word::SingleQuoted(l) => match shell {
+                Shell::Fish => Ok(format!("'{}'", l.replace('\\', "\\\\"))),
+                _ => Ok(format!("'{}'", l)),
+            },
+            ast::Word::DoubleQuoted(v) => {
+                let mut quoted = String::new();
+
+                for (index, word) in v.iter().enumerate() {
+                    quoted.push_str(&word.translate(shell)?);
+
+                    // Variables are delimited from the text following them by
+                    // closing and reopening the quotes
+                    if let (ast::SimpleWord::Param(_), Some(ast::SimpleWord::Literal(_))) =
+                        (word, v.get(index + 1))
+                    {
+                        quoted.push_str("\"\"");
+                    }
+                }
+
+                Ok(format!("\"{}\"", quoted))
+            }
+        }
+    }
+}
+
+impl Translatable for ast::DefaultSimpleWord {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        match self {
+            ast::SimpleWord::Param(p) => p.translate(shell),
+            ast::SimpleWord::Subst(s) => s.translate(shell),
+            w => Ok(w.into_string()),
+        }
+    }
+}
+
+impl Translatable for ast::DefaultParameter {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        let parameter = match (shell, self) {
+            (Shell::Csh, ast::Parameter::Var(v)) => format!("${{{}}}", v),
+            (_, ast::Parameter::Var(v)) => format!("${}", v),
+            (_, ast::Parameter::At) | (_, ast::Parameter::Star) => String::from("$argv"),
+            (Shell::Csh, ast::Parameter::Pound) => String::from("$#argv"),
+            (_, ast::Parameter::Pound) => String::from("(count $argv)"),
+            (_, ast::Parameter::Question) => String::from("$status"),
+            (Shell::Csh, ast::Parameter::Dollar) => String::from("$$"),
+            (_, ast::Parameter::Dollar) => String::from("$fish_pid"),
+            (Shell::Csh, ast::Parameter::Bang) => String::from("$!"),
+            (_, ast::Parameter::Bang) => String::from("$last_pid"),
+            (Shell::Csh, ast::Parameter::Positional(n)) => format!("${}", n),
+            (_, ast::Parameter::Positional(0)) => String::from("(status filename)"),
+            (_, ast::Parameter::Positional(n)) => format!("$argv[{}]", n),
+            (_, p) => return Err(unsupported(&p.into_string(), shell)),
+        };
+
+        Ok(parameter)
+    }
+}
+
+impl Translatable for ast::DefaultParameterSubstitution {
+    fn translate(&self, shell: Shell) -> Result<String, Error> {
+        match (shell, self) {
+            (Shell::Csh, ast::ParameterSubstitution::Command(cmds)) => {
+                Ok(format!("`{}`", translate_all(cmds, shell, "; ")?))
+            }
+            (_, ast::ParameterSubstitution::Command(cmds)) => {
+                Ok(format!("$({})", translate_all(cmds, shell, "; ")?))
+            }
+            (Shell::Csh, ast::ParameterSubstitution::Len(ast::Parameter::Var(v))) => {
+                Ok(format!("${{%{}}}", v))
+            }
+            (_, ast::ParameterSubstitution::Len(ast::Parameter::Var(v))) => {
+                Ok(format!("$(string length -- \"${}\")", v))
+            }
+            (Shell::Fish, ast::ParameterSubstitution::Arith(Some(a))) => {
+                Ok(format!("$(math \"{}\")", a.into_string()))
+            }
+            (_, s) => Err(unsupported(&s.into_string(), shell)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use conch_parser::lexer::Lexer;
+    use conch_parser::parse::DefaultParser;
+
+    fn translate(script: &str, shell: Shell) -> Result<String, Error> {
+        let cmds = DefaultParser::new(Lexer::new(script.chars()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        translate_all(&cmds, shell, "\n")
+    }
+
+    #[test]
+    fn test_translate_fish() {
+        assert_eq!(
+            translate("export PATH=/opt/bin:$PATH", Shell::Fish).unwrap(),
+            "set -gx PATH /opt/bin $PATH"
+        );
+        assert_eq!(
+            translate("export MANPATH='/a:b':$MANPATH: CC=cc:$X", Shell::Fish).unwrap(),
+            "set -gx MANPATH '/a:b' $MANPATH; set -gx CC cc:$X"
+        );
+        assert_eq!(
+            translate("if [ -n \"$HOST\" ]; then echo ${HOST}s; fi", Shell::Fish).unwrap(),
+            "if [ -n \"$HOST\" ]\necho {$HOST}s\nend"
+        );
+        assert_eq!(
+            translate("for x in a b; do echo $x 2>&1; done", Shell::Fish).unwrap(),
+            "for x in a b\necho $x 2>&1\nend"
+        );
+        assert_eq!(
+            translate("setup() { x=$(hostname); }", Shell::Fish).unwrap(),
+            "function setup\nset x $(hostname)\nend"
+        );
+        assert!(translate("echo ${x:-y}", Shell::Fish).is_err());
+    }
+
+    #[test]
+    fn test_translate_csh() {
+        assert_eq!(
+            translate("export PATH=/opt/bin:$PATH", Shell::Csh).unwrap(),
+            "setenv PATH /opt/bin:${PATH}"
+        );
+        assert_eq!(
+            translate("if true; then echo a; else echo b; fi", Shell::Csh).unwrap(),
+            "if ( { true } ) then\necho a\nelse\necho b\nendif"
+        );
+        assert_eq!(
+            translate("case $x in a|b) echo a ;; *) echo c ;; esac", Shell::Csh).unwrap(),
+            "switch ( ${x} )\ncase a:\ncase b:\necho a\nbreaksw\ndefault:\necho c\nbreaksw\nendsw"
+        );
+        assert_eq!(
+            translate("alias despacktivate='spack env deactivate' ll", Shell::Csh).unwrap(),
+            "alias despacktivate 'spack env deactivate'; alias ll"
+        );
+        assert!(translate("setup() { true; }", Shell::Csh).is_err());
+        assert!(translate("echo a 2> /dev/null", Shell::Csh).is_err());
+    }
+
+    #[test]
+    fn test_shell_function() {
+        let body = vec![String::from("setenv A 'b'"), String::from("setenv C d")];
+
+        assert_eq!(
+            Shell::Csh.function("load_x", "spack load x", &body),
+            "# spack load x\nalias load_x 'setenv A '\\''b'\\''; setenv C d'\n"
+        );
+        assert_eq!(
+            Shell::Fish.function("load_x", "spack load x", &body[1..]),
+            "function load_x\n# spack load x\nsetenv C d\nend\n"
+        );
+    }
+
+    #[test]
+    fn test_modify() {
+        let set = Modification::Set(String::from("it's"));
+        let append = Modification::Append(vec![String::from("/opt/a")]);
+        let remove = Modification::Remove(vec![String::from("/opt/a"), String::from("/b")]);
+
+        assert_eq!(Shell::Bash.modify("X", &set), "export X='it'\\''s'");
+        assert_eq!(Shell::Fish.modify("X", &set), "set -gx X 'it\\'s'");
+        assert_eq!(Shell::Csh.modify("X", &Modification::Unset), "unsetenv X");
+        assert_eq!(
+            Shell::Sh.modify("PATH", &append),
+            "export PATH=${PATH:+$PATH:}/opt/a"
+        );
+        assert_eq!(
+            Shell::Bash.modify("PATH", &remove),
+            "export PATH=\"$(printf '%s\\n' \"$PATH\" | tr : '\\n' | grep -vxF -e '/opt/a' -e '/b' | paste -sd: -)\""
+        );
+        assert_eq!(
+            Shell::Sh.modify("SPACK_LOADED_HASHES", &Modification::Add(vec![String::from("abcd")])),
+            "export SPACK_LOADED_HASHES=\"$(printf '%s\\n' \"$SPACK_LOADED_HASHES\" | tr : '\\n' | grep -vxF -e 'abcd' | paste -sd: -)\"; export SPACK_LOADED_HASHES=${SPACK_LOADED_HASHES:+$SPACK_LOADED_HASHES:}abcd"
+        );
+        // An empty value, as left by the removal of every entry, gets no separator
+        assert_eq!(
+            Shell::Fish.modify("PATH", &append),
+            "test -n \"$PATH\"; and set -gx PATH \"$PATH:/opt/a\"; or set -gx PATH \"/opt/a\""
+        );
+        assert_eq!(
+            Shell::Fish.modify("PATH", &remove),
+            "set -gx PATH (printenv PATH | tr : '\\n' | grep -vxF -e '/opt/a' -e '/b' | paste -sd: -)"
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use crate::Error;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Parameters holding compiler flags; their values are kept whole instead of being
+// split on commas
+static FLAG_PARAMETERS: [&str; 6] = [
+    "cflags", "cxxflags", "fflags", "cppflags", "ldflags", "ldlibs",
+];
+
+// Parameters describing the architecture of a spec
+pub static ARCH_PARAMETERS: [&str; 4] = ["arch", "platform", "os", "target"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compiler {
+    pub name: String,
+    pub versions: Option<String>,
+}
+
+// A spack spec, as in name@version%compiler@version+variant~variant key=value ^dep /hash
+//
+// Boolean variants are stored as parameters with a value of true or false, so that
+// +variant and variant=True are equivalent. Propagation (++, ~~, ==) is accepted
+// but not distinguished, as it has no effect on which installs a spec matches.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Spec {
+    pub name: Option<String>,
+    pub versions: Option<String>,
+    pub compiler: Option<Compiler>,
+    pub parameters: BTreeMap<String, Vec<String>>,
+    pub hash: Option<String>,
+    pub dependencies: Vec<Spec>,
+}
+
+impl Spec {
+    // Return true if the spec has no constraint at all
+    pub fn is_empty(&self) -> bool {
+        *self == Spec::default()
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tokens = vec![];
+        let mut head = String::new();
+
+        if let Some(name) = &self.name {
+            head.push_str(name);
+        }
+
+        if let Some(versions) = &self.versions {
+            head.push_str(&format!("@{}", versions));
+        }
+
+        if let Some(compiler) = &self.compiler {
+            head.push_str(&format!("%{}", compiler.name));
+            if let Some(versions) = &compiler.versions {
+                head.push_str(&format!("@{}", versions));
+            }
+        }
+
+        for (key, values) in self.parameters.iter() {
+            match values.as_slice() {
+                [value] if value == "true" => head.push_str(&format!("+{}", key)),
+                [value] if value == "false" => head.push_str(&format!("~{}", key)),
+                _ => (),
+            }
+        }
+
+        if !head.is_empty() {
+            tokens.push(head);
+        }
+
+        for (key, values) in self.parameters.iter() {
+            match values.as_slice() {
+                [value] if value == "true" || value == "false" => (),
+                _ => {
+                    let value = values.join(",");
+                    if value.contains(char::is_whitespace) {
+                        tokens.push(format!("{}=\"{}\"", key, value));
+                    } else {
+                        tokens.push(format!("{}={}", key, value));
+                    }
+                }
+            }
+        }
+
+        if let Some(hash) = &self.hash {
+            tokens.push(format!("/{}", hash));
+        }
+
+        let mut dependencies = self
+            .dependencies
+            .iter()
+            .map(|d| format!("^{}", d))
+            .collect::<Vec<String>>();
+        dependencies.sort();
+        tokens.append(&mut dependencies);
+
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+fn is_version_char(c: char) -> bool {
+    is_name_char(c) || c == ':' || c == ',' || c == '='
+}
+
+// Consume characters from the start of the input while they satisfy the predicate
+fn take_while<'a>(input: &mut &'a str, predicate: fn(char) -> bool) -> &'a str {
+    let end = input.find(|c| !predicate(c)).unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    *input = rest;
+    token
+}
+
+fn take_token<'a>(
+    input: &mut &'a str,
+    predicate: fn(char) -> bool,
+    what: &str,
+) -> Result<&'a str, Error> {
+    match take_while(input, predicate) {
+        "" => Err(Error::Spec(format!("Expected {}", what))),
+        token => Ok(token),
+    }
+}
+
+fn set_parameter(spec: &mut Spec, key: &str, value: &str) {
+    let value = value.trim_matches(|c| c == '"' || c == '\'');
+
+    let values = match value.to_lowercase().as_str() {
+        "true" | "false" => vec![value.to_lowercase()],
+        _ if FLAG_PARAMETERS.contains(&key) => vec![value.to_string()],
+        _ => value.split(',').map(String::from).collect(),
+    };
+
+    spec.parameters.insert(key.to_string(), values);
+}
+
+// Parse the spec arguments of a spack command; each item is a shell word, as
+// values in key=value pairs may contain spaces once unquoted
+pub fn parse(words: &[&str]) -> Result<Vec<Spec>, Error> {
+    let mut specs: Vec<Spec> = vec![];
+    // Whether constraints apply to the last dependency of the last root spec
+    let mut in_dependency = false;
+
+    fn current(specs: &mut Vec<Spec>, in_dependency: bool) -> &mut Spec {
+        if specs.is_empty() {
+            specs.push(Spec::default());
+        }
+
+        let root = specs.last_mut().unwrap();
+        if in_dependency {
+            root.dependencies.last_mut().unwrap()
+        } else {
+            root
+        }
+    }
+
+    for word in words {
+        let mut input: &str = word;
+
+        while let Some(c) = input.chars().next() {
+            match c {
+                ' ' | '\t' | '\n' => input = &input[1..],
+                '^' => {
+                    input = &input[1..];
+                    current(&mut specs, false)
+                        .dependencies
+                        .push(Spec::default());
+                    in_dependency = true;
+                }
+                '@' => {
+                    input = &input[1..];
+                    let versions = take_token(&mut input, is_version_char, "a version")?;
+                    let spec = current(&mut specs, in_dependency);
+                    if spec.versions.is_some() {
+                        return Err(Error::Spec(format!("Multiple versions in {}", word)));
+                    }
+                    spec.versions = Some(versions.to_string());
+                }
+                '%' => {
+                    input = &input[1..];
+                    let name = take_token(&mut input, is_name_char, "a compiler name")?;
+                    let versions = match input.strip_prefix('@') {
+                        Some(rest) => {
+                            input = rest;
+                            Some(take_token(&mut input, is_version_char, "a version")?.to_string())
+                        }
+                        None => None,
+                    };
+
+                    let spec = current(&mut specs, in_dependency);
+                    if spec.compiler.is_some() {
+                        return Err(Error::Spec(format!("Multiple compilers in {}", word)));
+                    }
+                    spec.compiler = Some(Compiler {
+                        name: name.to_string(),
+                        versions,
+                    });
+                }
+                '+' | '~' => {
+                    input = input.trim_start_matches(c);
+                    let name = take_token(&mut input, is_name_char, "a variant name")?;
+                    let value = if c == '+' { "true" } else { "false" };
+                    set_parameter(current(&mut specs, in_dependency), name, value);
+                }
+                '/' => {
+                    input = &input[1..];
+                    let hash = take_token(&mut input, |c| c.is_ascii_alphanumeric(), "a hash")?;
+                    let spec = current(&mut specs, in_dependency);
+                    if spec.hash.is_none() {
+                        spec.hash = Some(hash.to_string());
+                    } else {
+                        // A hash following a complete spec designates another one
+                        specs.push(Spec {
+                            hash: Some(hash.to_string()),
+                            ..Spec::default()
+                        });
+                        in_dependency = false;
+                    }
+                }
+                c if is_name_char(c) && c != '-' && c != '.' => {
+                    let name = take_while(&mut input, is_name_char);
+
+                    if let Some(rest) = input.strip_prefix('=') {
+                        // The value extends to the end of the word
+                        let value = rest.trim_start_matches('=');
+                        input = "";
+                        set_parameter(current(&mut specs, in_dependency), name, value);
+                    } else {
+                        let spec = current(&mut specs, in_dependency);
+                        if spec.name.is_none() && spec.hash.is_none() {
+                            spec.name = Some(name.to_string());
+                        } else {
+                            // A name following a complete spec starts a new one
+                            specs.push(Spec {
+                                name: Some(name.to_string()),
+                                ..Spec::default()
+                            });
+                            in_dependency = false;
+                        }
+                    }
+                }
+                c => {
+                    return Err(Error::Spec(format!(
+                        "Unexpected character '{}' in {}",
+                        c, word
+                    )))
+                }
+            }
+        }
+    }
+
+    match specs
+        .iter()
+        .find(|s| s.dependencies.iter().any(Spec::is_empty))
+    {
+        Some(spec) => Err(Error::Spec(format!("Empty dependency in {}", spec))),
+        None => Ok(specs),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Component {
+    Number(u64),
+    Text(String),
+}
+
+impl PartialOrd for Component {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Component {
+    // Numbers sort after text, so that 1.0 comes after 1.0rc
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Component::Number(a), Component::Number(b)) => a.cmp(b),
+            (Component::Text(a), Component::Text(b)) => a.cmp(b),
+            (Component::Number(_), Component::Text(_)) => Ordering::Greater,
+            (Component::Text(_), Component::Number(_)) => Ordering::Less,
+        }
+    }
+}
+
+fn components(version: &str) -> Vec<Component> {
+    version
+        .split(['.', '-', '_'])
+        .filter(|c| !c.is_empty())
+        .map(|c| match c.parse::<u64>() {
+            Ok(n) => Component::Number(n),
+            Err(_) => Component::Text(c.to_string()),
+        })
+        .collect()
+}
+
+// Return true if the version is constrained by the bound, i.e. equal to it or a
+// more specific release of it
+fn within(version: &[Component], bound: &[Component]) -> bool {
+    version.len() >= bound.len() && version[..bound.len()] == *bound
+}
+
+// Return true if a concrete version satisfies a constraint such as 1.2, =1.2,
+// 1.2:1.4, :1.4 or 1.2:, or a comma-separated list of those
+pub fn version_satisfies(version: &str, constraint: &str) -> bool {
+    let version = version.rsplit('=').next().unwrap_or(version);
+    let concrete = components(version);
+
+    constraint.split(',').any(|constraint| {
+        if let Some(exact) = constraint.strip_prefix('=') {
+            return exact == version;
+        }
+
+        match constraint.split_once(':') {
+            Some((low, high)) => {
+                let low = components(low);
+                let high = components(high);
+
+                (low.is_empty() || concrete >= low)
+                    && (high.is_empty() || concrete <= high || within(&concrete, &high))
+            }
+            None => within(&concrete, &components(constraint)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_one(spec: &str) -> Spec {
+        let words = spec.split(' ').collect::<Vec<&str>>();
+        let mut specs = parse(&words).unwrap();
+        assert_eq!(specs.len(), 1);
+        specs.remove(0)
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = parse_one("trilinos@13.2%gcc@9.3.0+mpi~debug build_type=Release ^mpich@3.4");
+
+        assert_eq!(spec.name, Some(String::from("trilinos")));
+        assert_eq!(spec.versions, Some(String::from("13.2")));
+        assert_eq!(
+            spec.compiler,
+            Some(Compiler {
+                name: String::from("gcc"),
+                versions: Some(String::from("9.3.0"))
+            })
+        );
+        assert_eq!(spec.parameters["mpi"], vec!["true"]);
+        assert_eq!(spec.parameters["debug"], vec!["false"]);
+        assert_eq!(spec.parameters["build_type"], vec!["Release"]);
+        assert_eq!(spec.dependencies[0].name, Some(String::from("mpich")));
+        assert_eq!(spec.dependencies[0].versions, Some(String::from("3.4")));
+    }
+
+    #[test]
+    fn test_parse_multiple_specs() {
+        let specs = parse(&["zlib", "cmake@3:", "^zlib", "^ncurses", "/abcdef", "/12345"]).unwrap();
+
+        assert_eq!(specs.len(), 3);
+        assert_eq!(specs[1].to_string(), "cmake@3: ^ncurses /abcdef ^zlib");
+        assert_eq!(specs[2].hash, Some(String::from("12345")));
+    }
+
+    #[test]
+    fn test_spec_normalization() {
+        assert_eq!(
+            parse_one("foo ~b +a shared=True").to_string(),
+            parse_one("foo+a+shared~b").to_string()
+        );
+        assert_eq!(
+            parse(&["foo", "cflags=-O3 -g"]).unwrap()[0].to_string(),
+            "foo cflags=\"-O3 -g\""
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&["foo@"]).is_err());
+        assert!(parse(&["foo@1@2"]).is_err());
+        assert!(parse(&["foo", "^"]).is_err());
+        assert!(parse(&["foo!"]).is_err());
+    }
+
+    #[test]
+    fn test_version_satisfies() {
+        assert!(version_satisfies("1.2.3", "1.2"));
+        assert!(!version_satisfies("1.20", "1.2"));
+        assert!(!version_satisfies("1.2.3", "=1.2"));
+        assert!(version_satisfies("1.3", "1.2:1.4"));
+        assert!(version_satisfies("1.4.2", "1.2:1.4"));
+        assert!(!version_satisfies("1.5", "1.2:1.4"));
+        assert!(version_satisfies("13.2.0", "13:"));
+        assert!(version_satisfies("2.0", ":1.4,2"));
+    }
+}
@@ -1,6 +1,8 @@
 #![allow(unused_imports)]
+use crate::Error;
 use conch_parser::ast;
 use regex::Regex;
+use std::rc::Rc;
 
 #[macro_export]
 macro_rules! command_word {
@@ -62,17 +64,550 @@ impl ExtractCommand for ast::TopLevelCommand<String> {
 }
 
 pub trait FindCommandWord {
-    // Return a SimpleCommand if it starts with the given string
-    fn position(&self, value: &str) -> Option<usize>;
+    // Return the index of the first word matching the given regular expression
+    fn position(&self, pattern: &str) -> Result<Option<usize>, Error>;
 }
 
 impl FindCommandWord for ast::DefaultSimpleCommand {
-    // Return a SimpleCommand if it starts with the given string
-    fn position(&self, value: &str) -> Option<usize> {
-        let re = Regex::new(value).unwrap();
-        self.redirects_or_cmd_words.iter().position(|x| match x {
-            command_word!(w) if re.is_match(w) => true,
-            _ => false,
+    fn position(&self, pattern: &str) -> Result<Option<usize>, Error> {
+        let re = Regex::new(pattern)?;
+        Ok(self
+            .redirects_or_cmd_words
+            .iter()
+            .position(|x| matches!(x, command_word!(w) if re.is_match(w))))
+    }
+}
+
+// Return the value of a simple word, if it does not depend on any expansion
+pub(crate) fn simple_literal(word: &ast::DefaultSimpleWord, quoted: bool) -> Option<String> {
+    match word {
+        ast::SimpleWord::Literal(l) | ast::SimpleWord::Escaped(l) => Some(l.clone()),
+        ast::SimpleWord::Colon => Some(String::from(":")),
+        ast::SimpleWord::Param(_) | ast::SimpleWord::Subst(_) => None,
+        // Outside of quotes, the following are subject to expansion
+        _ if !quoted => None,
+        ast::SimpleWord::Star => Some(String::from("*")),
+        ast::SimpleWord::Question => Some(String::from("?")),
+        ast::SimpleWord::SquareOpen => Some(String::from("[")),
+        ast::SimpleWord::SquareClose => Some(String::from("]")),
+        ast::SimpleWord::Tilde => Some(String::from("~")),
+    }
+}
+
+// Return the value of a word once unquoted, if it does not depend on any expansion
+pub fn literal(word: &ast::TopLevelWord<String>) -> Option<String> {
+    let words = match &word.0 {
+        ast::ComplexWord::Single(w) => vec![w],
+        ast::ComplexWord::Concat(v) => v.iter().collect(),
+    };
+
+    words
+        .iter()
+        .enumerate()
+        .map(|(index, w)| match w {
+            // Tildes only expand to a home directory at the start of a word; we
+            // consider those followed by a name to be spec variants, as in ~debug
+            ast::Word::Simple(ast::SimpleWord::Tilde) => match words.get(index + 1) {
+                Some(ast::Word::Simple(ast::SimpleWord::Literal(l))) if !l.starts_with('/') => {
+                    Some(String::from("~"))
+                }
+                _ if index > 0 => Some(String::from("~")),
+                _ => None,
+            },
+            ast::Word::Simple(w) => simple_literal(w, false),
+            ast::Word::SingleQuoted(l) => Some(l.clone()),
+            ast::Word::DoubleQuoted(v) => v.iter().map(|w| simple_literal(w, true)).collect(),
         })
+        .collect()
+}
+
+macro_rules! visitor {
+    // Define a visitor trait over the default AST; `visit_*` methods are the hooks
+    // to override, `walk_*` methods recurse into the children of a node. The
+    // mutability token selects between a read-only and a mutating visitor, and
+    // $shared converts the reference-counted function bodies
+    ($(#[$attr:meta])* $Visitor:ident, $shared:path, $($mutability:tt)?) => {
+        $(#[$attr])*
+        pub trait $Visitor {
+            fn visit_top_level_command(&mut self, cmd: &$($mutability)? ast::TopLevelCommand<String>) {
+                self.walk_top_level_command(cmd)
+            }
+
+            fn walk_top_level_command(&mut self, cmd: &$($mutability)? ast::TopLevelCommand<String>) {
+                match &$($mutability)? cmd.0 {
+                    ast::Command::Job(list) | ast::Command::List(list) => {
+                        self.visit_and_or_list(list)
+                    }
+                }
+            }
+
+            fn visit_and_or_list(&mut self, list: &$($mutability)? ast::DefaultAndOrList) {
+                self.walk_and_or_list(list)
+            }
+
+            fn walk_and_or_list(&mut self, list: &$($mutability)? ast::DefaultAndOrList) {
+                self.visit_listable_command(&$($mutability)? list.first);
+                for and_or in &$($mutability)? list.rest {
+                    match and_or {
+                        ast::AndOr::And(cmd) | ast::AndOr::Or(cmd) => {
+                            self.visit_listable_command(cmd)
+                        }
+                    }
+                }
+            }
+
+            fn visit_listable_command(&mut self, cmd: &$($mutability)? ast::DefaultListableCommand) {
+                self.walk_listable_command(cmd)
+            }
+
+            fn walk_listable_command(&mut self, cmd: &$($mutability)? ast::DefaultListableCommand) {
+                match cmd {
+                    ast::ListableCommand::Single(cmd) => self.visit_pipeable_command(cmd),
+                    ast::ListableCommand::Pipe(_, cmds) => {
+                        for cmd in cmds {
+                            self.visit_pipeable_command(cmd)
+                        }
+                    }
+                }
+            }
+
+            fn visit_pipeable_command(&mut self, cmd: &$($mutability)? ast::DefaultPipeableCommand) {
+                self.walk_pipeable_command(cmd)
+            }
+
+            fn walk_pipeable_command(&mut self, cmd: &$($mutability)? ast::DefaultPipeableCommand) {
+                match cmd {
+                    ast::PipeableCommand::Simple(cmd) => self.visit_simple_command(cmd),
+                    ast::PipeableCommand::Compound(cmd) => self.visit_compound_command(cmd),
+                    ast::PipeableCommand::FunctionDef(name, body) => {
+                        self.visit_function_def(name, $shared(body))
+                    }
+                }
+            }
+
+            fn visit_function_def(
+                &mut self,
+                name: &$($mutability)? String,
+                body: &$($mutability)? ast::DefaultCompoundCommand,
+            ) {
+                self.walk_function_def(name, body)
+            }
+
+            fn walk_function_def(
+                &mut self,
+                _name: &$($mutability)? String,
+                body: &$($mutability)? ast::DefaultCompoundCommand,
+            ) {
+                self.visit_compound_command(body)
+            }
+
+            fn visit_simple_command(&mut self, cmd: &$($mutability)? ast::DefaultSimpleCommand) {
+                self.walk_simple_command(cmd)
+            }
+
+            fn walk_simple_command(&mut self, cmd: &$($mutability)? ast::DefaultSimpleCommand) {
+                for roev in &$($mutability)? cmd.redirects_or_env_vars {
+                    match roev {
+                        ast::RedirectOrEnvVar::Redirect(redirect) => self.visit_redirect(redirect),
+                        ast::RedirectOrEnvVar::EnvVar(name, value) => {
+                            self.visit_env_var(name, value)
+                        }
+                    }
+                }
+
+                for rocw in &$($mutability)? cmd.redirects_or_cmd_words {
+                    match rocw {
+                        ast::RedirectOrCmdWord::Redirect(redirect) => self.visit_redirect(redirect),
+                        ast::RedirectOrCmdWord::CmdWord(word) => self.visit_top_level_word(word),
+                    }
+                }
+            }
+
+            fn visit_env_var(
+                &mut self,
+                name: &$($mutability)? String,
+                value: &$($mutability)? Option<ast::TopLevelWord<String>>,
+            ) {
+                self.walk_env_var(name, value)
+            }
+
+            fn walk_env_var(
+                &mut self,
+                _name: &$($mutability)? String,
+                value: &$($mutability)? Option<ast::TopLevelWord<String>>,
+            ) {
+                if let Some(word) = value {
+                    self.visit_top_level_word(word)
+                }
+            }
+
+            fn visit_compound_command(&mut self, cmd: &$($mutability)? ast::DefaultCompoundCommand) {
+                self.walk_compound_command(cmd)
+            }
+
+            fn walk_compound_command(&mut self, cmd: &$($mutability)? ast::DefaultCompoundCommand) {
+                match &$($mutability)? cmd.kind {
+                    ast::CompoundCommandKind::Brace(body)
+                    | ast::CompoundCommandKind::Subshell(body) => {
+                        for cmd in body {
+                            self.visit_top_level_command(cmd)
+                        }
+                    }
+                    ast::CompoundCommandKind::While(gbp) | ast::CompoundCommandKind::Until(gbp) => {
+                        self.visit_guard_body_pair(gbp)
+                    }
+                    ast::CompoundCommandKind::If {
+                        conditionals,
+                        else_branch,
+                    } => {
+                        for gbp in conditionals {
+                            self.visit_guard_body_pair(gbp)
+                        }
+                        for cmd in else_branch.into_iter().flatten() {
+                            self.visit_top_level_command(cmd)
+                        }
+                    }
+                    ast::CompoundCommandKind::For { words, body, .. } => {
+                        for word in words.into_iter().flatten() {
+                            self.visit_top_level_word(word)
+                        }
+                        for cmd in body {
+                            self.visit_top_level_command(cmd)
+                        }
+                    }
+                    ast::CompoundCommandKind::Case { word, arms } => {
+                        self.visit_top_level_word(word);
+                        for arm in arms {
+                            self.visit_pattern_body_pair(arm)
+                        }
+                    }
+                }
+
+                for redirect in &$($mutability)? cmd.io {
+                    self.visit_redirect(redirect)
+                }
+            }
+
+            fn visit_guard_body_pair(
+                &mut self,
+                gbp: &$($mutability)? ast::GuardBodyPair<ast::TopLevelCommand<String>>,
+            ) {
+                self.walk_guard_body_pair(gbp)
+            }
+
+            fn walk_guard_body_pair(
+                &mut self,
+                gbp: &$($mutability)? ast::GuardBodyPair<ast::TopLevelCommand<String>>,
+            ) {
+                for cmd in &$($mutability)? gbp.guard {
+                    self.visit_top_level_command(cmd)
+                }
+                for cmd in &$($mutability)? gbp.body {
+                    self.visit_top_level_command(cmd)
+                }
+            }
+
+            fn visit_pattern_body_pair(
+                &mut self,
+                pbp: &$($mutability)? ast::PatternBodyPair<
+                    ast::TopLevelWord<String>,
+                    ast::TopLevelCommand<String>,
+                >,
+            ) {
+                self.walk_pattern_body_pair(pbp)
+            }
+
+            fn walk_pattern_body_pair(
+                &mut self,
+                pbp: &$($mutability)? ast::PatternBodyPair<
+                    ast::TopLevelWord<String>,
+                    ast::TopLevelCommand<String>,
+                >,
+            ) {
+                for word in &$($mutability)? pbp.patterns {
+                    self.visit_top_level_word(word)
+                }
+                for cmd in &$($mutability)? pbp.body {
+                    self.visit_top_level_command(cmd)
+                }
+            }
+
+            fn visit_redirect(&mut self, redirect: &$($mutability)? ast::DefaultRedirect) {
+                self.walk_redirect(redirect)
+            }
+
+            fn walk_redirect(&mut self, redirect: &$($mutability)? ast::DefaultRedirect) {
+                match redirect {
+                    ast::Redirect::Read(_, word)
+                    | ast::Redirect::Write(_, word)
+                    | ast::Redirect::ReadWrite(_, word)
+                    | ast::Redirect::Append(_, word)
+                    | ast::Redirect::Clobber(_, word)
+                    | ast::Redirect::Heredoc(_, word)
+                    | ast::Redirect::DupRead(_, word)
+                    | ast::Redirect::DupWrite(_, word) => self.visit_top_level_word(word),
+                }
+            }
+
+            fn visit_top_level_word(&mut self, word: &$($mutability)? ast::TopLevelWord<String>) {
+                self.walk_top_level_word(word)
+            }
+
+            fn walk_top_level_word(&mut self, word: &$($mutability)? ast::TopLevelWord<String>) {
+                self.visit_complex_word(&$($mutability)? word.0)
+            }
+
+            fn visit_complex_word(&mut self, word: &$($mutability)? ast::DefaultComplexWord) {
+                self.walk_complex_word(word)
+            }
+
+            fn walk_complex_word(&mut self, word: &$($mutability)? ast::DefaultComplexWord) {
+                match word {
+                    ast::ComplexWord::Single(word) => self.visit_word(word),
+                    ast::ComplexWord::Concat(words) => {
+                        for word in words {
+                            self.visit_word(word)
+                        }
+                    }
+                }
+            }
+
+            fn visit_word(&mut self, word: &$($mutability)? ast::DefaultWord) {
+                self.walk_word(word)
+            }
+
+            fn walk_word(&mut self, word: &$($mutability)? ast::DefaultWord) {
+                match word {
+                    ast::Word::Simple(word) => self.visit_simple_word(word),
+                    ast::Word::DoubleQuoted(words) => {
+                        for word in words {
+                            self.visit_simple_word(word)
+                        }
+                    }
+                    ast::Word::SingleQuoted(_) => (),
+                }
+            }
+
+            fn visit_simple_word(&mut self, word: &$($mutability)? ast::DefaultSimpleWord) {
+                self.walk_simple_word(word)
+            }
+
+            fn walk_simple_word(&mut self, word: &$($mutability)? ast::DefaultSimpleWord) {
+                match word {
+                    ast::SimpleWord::Param(parameter) => self.visit_parameter(parameter),
+                    ast::SimpleWord::Subst(substitution) => {
+                        self.visit_parameter_substitution(substitution)
+                    }
+                    _ => (),
+                }
+            }
+
+            fn visit_parameter(&mut self, _parameter: &$($mutability)? ast::DefaultParameter) {}
+
+            fn visit_parameter_substitution(
+                &mut self,
+                substitution: &$($mutability)? ast::DefaultParameterSubstitution,
+            ) {
+                self.walk_parameter_substitution(substitution)
+            }
+
+            fn walk_parameter_substitution(
+                &mut self,
+                substitution: &$($mutability)? ast::DefaultParameterSubstitution,
+            ) {
+                match substitution {
+                    ast::ParameterSubstitution::Command(cmds) => {
+                        for cmd in cmds {
+                            self.visit_top_level_command(cmd)
+                        }
+                    }
+                    ast::ParameterSubstitution::Len(parameter) => self.visit_parameter(parameter),
+                    ast::ParameterSubstitution::Arith(arithmetic) => {
+                        if let Some(arithmetic) = arithmetic {
+                            self.visit_arithmetic(arithmetic)
+                        }
+                    }
+                    ast::ParameterSubstitution::Default(_, parameter, word)
+                    | ast::ParameterSubstitution::Assign(_, parameter, word)
+                    | ast::ParameterSubstitution::Error(_, parameter, word)
+                    | ast::ParameterSubstitution::Alternative(_, parameter, word)
+                    | ast::ParameterSubstitution::RemoveSmallestSuffix(parameter, word)
+                    | ast::ParameterSubstitution::RemoveLargestSuffix(parameter, word)
+                    | ast::ParameterSubstitution::RemoveSmallestPrefix(parameter, word)
+                    | ast::ParameterSubstitution::RemoveLargestPrefix(parameter, word) => {
+                        self.visit_parameter(parameter);
+                        if let Some(word) = word {
+                            self.visit_top_level_word(word)
+                        }
+                    }
+                }
+            }
+
+            fn visit_arithmetic(&mut self, arithmetic: &$($mutability)? ast::DefaultArithmetic) {
+                self.walk_arithmetic(arithmetic)
+            }
+
+            fn walk_arithmetic(&mut self, arithmetic: &$($mutability)? ast::DefaultArithmetic) {
+                match arithmetic {
+                    ast::Arithmetic::Var(_)
+                    | ast::Arithmetic::Literal(_)
+                    | ast::Arithmetic::PostIncr(_)
+                    | ast::Arithmetic::PostDecr(_)
+                    | ast::Arithmetic::PreIncr(_)
+                    | ast::Arithmetic::PreDecr(_) => (),
+                    ast::Arithmetic::UnaryPlus(value)
+                    | ast::Arithmetic::UnaryMinus(value)
+                    | ast::Arithmetic::LogicalNot(value)
+                    | ast::Arithmetic::BitwiseNot(value)
+                    | ast::Arithmetic::Assign(_, value) => self.visit_arithmetic(value),
+                    ast::Arithmetic::Pow(lhs, rhs)
+                    | ast::Arithmetic::Mult(lhs, rhs)
+                    | ast::Arithmetic::Div(lhs, rhs)
+                    | ast::Arithmetic::Modulo(lhs, rhs)
+                    | ast::Arithmetic::Add(lhs, rhs)
+                    | ast::Arithmetic::Sub(lhs, rhs)
+                    | ast::Arithmetic::ShiftLeft(lhs, rhs)
+                    | ast::Arithmetic::ShiftRight(lhs, rhs)
+                    | ast::Arithmetic::Less(lhs, rhs)
+                    | ast::Arithmetic::LessEq(lhs, rhs)
+                    | ast::Arithmetic::Great(lhs, rhs)
+                    | ast::Arithmetic::GreatEq(lhs, rhs)
+                    | ast::Arithmetic::Eq(lhs, rhs)
+                    | ast::Arithmetic::NotEq(lhs, rhs)
+                    | ast::Arithmetic::BitwiseAnd(lhs, rhs)
+                    | ast::Arithmetic::BitwiseXor(lhs, rhs)
+                    | ast::Arithmetic::BitwiseOr(lhs, rhs)
+                    | ast::Arithmetic::LogicalAnd(lhs, rhs)
+                    | ast::Arithmetic::LogicalOr(lhs, rhs) => {
+                        self.visit_arithmetic(lhs);
+                        self.visit_arithmetic(rhs)
+                    }
+                    ast::Arithmetic::Ternary(cond, lhs, rhs) => {
+                        self.visit_arithmetic(cond);
+                        self.visit_arithmetic(lhs);
+                        self.visit_arithmetic(rhs)
+                    }
+                    ast::Arithmetic::Sequence(values) => {
+                        for value in values {
+                            self.visit_arithmetic(value)
+                        }
+                    }
+                }
+            }
+        }
+    };
+}
+
+visitor! {
+    /// Read-only traversal of a parsed script
+    Visitor, Rc::as_ref,
+}
+
+visitor! {
+    /// Mutating traversal of a parsed script; shared function bodies are cloned
+    /// before being modified if they are referenced elsewhere
+    VisitorMut, Rc::make_mut, mut
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use conch_parser::lexer::Lexer;
+    use conch_parser::parse::DefaultParser;
+
+    fn parse(script: &str) -> Vec<ast::TopLevelCommand<String>> {
+        DefaultParser::new(Lexer::new(script.chars()))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    // Count the simple commands starting with a given word
+    struct Counter(&'static str, usize);
+
+    impl Visitor for Counter {
+        fn visit_simple_command(&mut self, cmd: &ast::DefaultSimpleCommand) {
+            if matches!(cmd.redirects_or_cmd_words.first(), Some(command_word!(w)) if w == self.0) {
+                self.1 += 1;
+            }
+            self.walk_simple_command(cmd)
+        }
+    }
+
+    // Rename every variable, wherever it appears
+    struct Renamer;
+
+    impl VisitorMut for Renamer {
+        fn visit_parameter(&mut self, parameter: &mut ast::DefaultParameter) {
+            if let ast::Parameter::Var(name) = parameter {
+                name.make_ascii_uppercase();
+            }
+        }
+    }
+
+    #[test]
+    fn test_literal() {
+        let literals = parse("spack load cmake@3:~b 'a b'\"$c\" \"cflags=-O3 -g\" * ~/x")
+            .iter_mut()
+            .flat_map(|cmd| cmd.extract("spack").unwrap().redirects_or_cmd_words.clone())
+            .map(|w| match w {
+                ast::RedirectOrCmdWord::CmdWord(w) => literal(&w),
+                _ => None,
+            })
+            .collect::<Vec<Option<String>>>();
+
+        assert_eq!(literals[2], Some(String::from("cmake@3:~b")));
+        assert_eq!(literals[3], None);
+        assert_eq!(literals[4], Some(String::from("cflags=-O3 -g")));
+        assert_eq!(literals[5], None);
+        assert_eq!(literals[6], None);
+    }
+
+    #[test]
+    fn test_position() {
+        let mut script = parse(". /spack/share/spack/setup-env.sh");
+        let source = script[0].extract(".").unwrap();
+
+        assert_eq!(source.position(".*setup-env.sh").unwrap(), Some(1));
+        assert_eq!(source.position("^spack$").unwrap(), None);
+        assert!(matches!(
+            source.position("setup-env("),
+            Err(Error::Regex(_))
+        ));
+    }
+
+    #[test]
+    fn test_visitor_nested() {
+        let script = parse(
+            "if [ -n \"$HOST\" ]; then spack load a; else spack load b; fi
+for x in 1 2; do spack load c; done
+while false; do { spack load d; }; done
+case $x in a) ( spack load e ) ;; esac
+setup() { true && spack load f | cat; }
+echo $(spack location -i g) spack",
+        );
+
+        let mut counter = Counter("spack", 0);
+        for cmd in &script {
+            counter.visit_top_level_command(cmd);
+        }
+        assert_eq!(counter.1, 7);
+
+        let mut counter = Counter("echo", 0);
+        for cmd in &script {
+            counter.visit_top_level_command(cmd);
+        }
+        assert_eq!(counter.1, 1);
+    }
+
+    #[test]
+    fn test_visitor_mut_words() {
+        let mut script = parse("x=\"${a:-$b}\" cmd $((c + 1)) > $d");
+        let expected = parse("x=\"${A:-$B}\" cmd $((c + 1)) > $D");
+
+        for cmd in &mut script {
+            Renamer.visit_top_level_command(cmd);
+        }
+        assert_eq!(script, expected);
     }
 }
@@ -0,0 +1,1502 @@
+use crate::database::{self, Database};
+use crate::environment::{self, Environment, Modification};
+use crate::parse::{self, Line, Unparsed};
+use crate::serializable::{Indent, Serializable};
+use crate::shell::{Shell, Translatable};
+use crate::spec;
+use crate::transform::{literal, simple_literal, ExtractCommand, FindCommandWord, VisitorMut};
+use crate::{command_word, Error};
+use conch_parser::ast;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Write};
+use std::io;
+use std::process::Command;
+
+static COMPILE_FUNC_NAME: &str = "__unspacked_rs_compile";
+static RELATIVE_FUNC_NAME: &str = "__unspacked_rs_relative";
+
+// The default prefix of the functions replacing the spack calls
+static FUNCTION_PREFIX: &str = "unspack_";
+
+// Matches the path of the script setting up spack in a shell
+static SPACK_SETUP: &str = ".*setup-env.sh";
+
+// The spack subcommands we know how to compile; each supports the shell flags
+static SPACK_VERBS: [&[&str]; 4] = [
+    &["load"],
+    &["unload"],
+    &["env", "activate"],
+    &["env", "deactivate"],
+];
+
+// Return the index of the first word of a command equal to the given one
+fn word_index(cmd: &ast::DefaultSimpleCommand, word: &str) -> Option<usize> {
+    cmd.redirects_or_cmd_words
+        .iter()
+        .position(|w| matches!(w, command_word!(w) if w == word))
+}
+
+// Return the subcommand of a spack call along with the index of its last word
+fn spack_verb(cmd: &ast::DefaultSimpleCommand) -> Option<(&'static [&'static str], usize)> {
+    let words = &cmd.redirects_or_cmd_words;
+
+    // Pick the verb appearing first, to avoid mistaking a spec for a subcommand
+    SPACK_VERBS
+        .iter()
+        .filter_map(|verb| {
+            let index = word_index(cmd, verb[0])?;
+            let matching = verb.iter().enumerate().all(|(offset, part)| {
+                matches!(words.get(index + offset), Some(command_word!(w)) if w == part)
+            });
+
+            if matching {
+                Some((index, index + verb.len() - 1, *verb))
+            } else {
+                None
+            }
+        })
+        .min()
+        .map(|(_, last, verb)| (verb, last))
+}
+
+// Return the subcommand of a spack call if we know how to compile it
+fn compilable(cmd: &ast::DefaultSimpleCommand) -> Option<&'static [&'static str]> {
+    match spack_verb(cmd) {
+        // spack load --list only prints information
+        Some((verb, _)) if verb == ["load"] && word_index(cmd, "--list").is_some() => None,
+        Some((verb, _)) => Some(verb),
+        None => None,
+    }
+}
+
+// Flags of the compiled spack subcommands expecting a value
+static VALUED_FLAGS: [&str; 5] = ["--only", "-d", "--dir", "-v", "--with-view"];
+
+// Split the arguments of a spack call following its subcommand into flags and
+// positional arguments; flags are joined with their value, as in --only=package.
+// Returns None if an argument is subject to expansion
+fn split_arguments(
+    call: &ast::DefaultSimpleCommand,
+    index: usize,
+) -> Option<(Vec<String>, Vec<String>)> {
+    let mut flags = vec![];
+    let mut positionals = vec![];
+
+    let mut words = call.redirects_or_cmd_words[index + 1..]
+        .iter()
+        .filter_map(|word| match word {
+            ast::RedirectOrCmdWord::CmdWord(w) => Some(w),
+            ast::RedirectOrCmdWord::Redirect(_) => None,
+        });
+
+    while let Some(word) = words.next() {
+        let word = literal(word)?;
+
+        if VALUED_FLAGS.contains(&word.as_str()) {
+            flags.push(format!("{}={}", word, literal(words.next()?)?));
+        } else if word.starts_with('-') {
+            flags.push(word);
+        } else {
+            positionals.push(word);
+        }
+    }
+
+    Some((flags, positionals))
+}
+
+// Describe what a spack call does, so that equivalent specs written differently
+// yield the same description; specs that fail to parse are reported
+fn normalize(call: &ast::DefaultSimpleCommand) -> Result<String, Error> {
+    let (verb, index) = match spack_verb(call) {
+        Some(verb) => verb,
+        None => return Ok(call.into_string()),
+    };
+
+    // Arguments depending on the environment are kept as is
+    let (mut flags, positionals) = match split_arguments(call, index) {
+        Some(arguments) => arguments,
+        None => return Ok(call.into_string()),
+    };
+    // Flags have the same effect in any order, unlike specs which are loaded in turn
+    flags.sort();
+
+    let arguments = if verb == ["load"] || verb == ["unload"] {
+        let words = positionals
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        spec::parse(&words)?
+            .iter()
+            .map(|spec| spec.to_string())
+            .collect()
+    } else {
+        positionals
+    };
+
+    // Assignments change how spack runs, as with SPACK_USER_CONFIG_PATH
+    let assignments = call
+        .redirects_or_env_vars
+        .iter()
+        .map(|assignment| assignment.into_string())
+        .collect::<Vec<String>>();
+
+    Ok([assignments, vec![verb.join(" ")], flags, arguments]
+        .concat()
+        .join(" "))
+}
+
+// Turn text into a shell identifier, joining its words with underscores
+fn identifier(text: &str) -> String {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("_")
+}
+
+// Describe a spack call in a few words fit for a function name, as in
+// load_trilinos_13_2; arguments subject to expansion are left out
+fn readable(call: &ast::DefaultSimpleCommand) -> String {
+    let (verb, index) = match spack_verb(call) {
+        Some(verb) => verb,
+        None => return String::from("spack"),
+    };
+
+    let mut words = verb.iter().map(|w| w.to_string()).collect::<Vec<String>>();
+
+    if let Some((flags, positionals)) = split_arguments(call, index) {
+        if verb == ["load"] || verb == ["unload"] {
+            let specs = positionals
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<&str>>();
+            for spec in spec::parse(&specs).unwrap_or_default() {
+                words.extend(spec.name.or(spec.hash));
+                words.extend(spec.versions);
+            }
+        } else {
+            // Environments given as a directory are named after it
+            words.extend(
+                flags
+                    .iter()
+                    .filter_map(|flag| flag.split_once('='))
+                    .filter(|(flag, _)| *flag == "-d" || *flag == "--dir")
+                    .filter_map(|(_, dir)| dir.trim_end_matches('/').rsplit('/').next())
+                    .map(String::from),
+            );
+            words.extend(positionals);
+        }
+    }
+
+    identifier(&words.join("_"))
+}
+
+// Return the name of the function undoing a compiled load, if it is one
+fn counterpart(prefix: &str, name: &str) -> Option<String> {
+    let name = name.strip_prefix(prefix)?;
+
+    if name == "load" || name.starts_with("load_") {
+        Some(format!("{}un{}", prefix, name))
+    } else {
+        None
+    }
+}
+
+// Rewrites spack calls found at any depth of a command, keeping track of them
+// across the lines of a script
+struct SpackRewriter<'a> {
+    // The prefix of the function names
+    prefix: &'a str,
+    spack_calls: Vec<(String, ast::DefaultSimpleCommand)>,
+    // The function names given to the calls, by description
+    names: HashMap<String, String>,
+    // Whether the calls get counterparts undoing them, whose names are reserved
+    counterparts: bool,
+    spack_source: ast::DefaultSimpleCommand,
+    errors: Vec<Error>,
+}
+
+impl<'a> SpackRewriter<'a> {
+    fn new(prefix: &'a str, counterparts: bool) -> Self {
+        SpackRewriter {
+            prefix,
+            spack_calls: vec![],
+            names: HashMap::new(),
+            counterparts,
+            spack_source: ast::DefaultSimpleCommand {
+                redirects_or_env_vars: vec![],
+                redirects_or_cmd_words: vec![],
+            },
+            errors: vec![],
+        }
+    }
+
+    // Name the function of a call after what it does; a short hash of its
+    // description is added if the name, or the one of its counterpart if it gets
+    // one, is taken
+    fn name(&self, call: &ast::DefaultSimpleCommand, description: &str) -> String {
+        let name = format!("{}{}", self.prefix, readable(call));
+        let counterpart = |name: &str| counterpart(self.prefix, name).filter(|_| self.counterparts);
+
+        let taken = |name: &str| {
+            self.names
+                .values()
+                .any(|other| other == name || counterpart(other).is_some_and(|c| c == name))
+        };
+
+        if taken(&name) || counterpart(&name).is_some_and(|c| taken(&c)) {
+            let mut hasher = Sha256::new();
+            hasher.update(description);
+            format!("{}_{}", name, &format!("{:x}", hasher.finalize())[..8])
+        } else {
+            name
+        }
+    }
+}
+
+impl VisitorMut for SpackRewriter<'_> {
+    // Replace a spack call with a call to its compiled function, or with a no-op if
+    // it cannot be compiled; the spack setup source script is also removed
+    fn visit_simple_command(&mut self, cmd: &mut ast::DefaultSimpleCommand) {
+        if let Some(source) = cmd.extract(".") {
+            match source.position(SPACK_SETUP) {
+                Ok(Some(_)) => {
+                    self.spack_source = source.clone();
+                    source.redirects_or_cmd_words = vec![command_word!(":")];
+                }
+                Ok(None) => (),
+                Err(e) => self.errors.push(e),
+            }
+        } else if let Some(spack) = cmd.extract("spack") {
+            if compilable(spack).is_some() {
+                // Assignments are kept for spack to run with, while redirects stay
+                // on the call site
+                let mut spack_call = spack.clone();
+                spack_call
+                    .redirects_or_env_vars
+                    .retain(|v| matches!(v, ast::RedirectOrEnvVar::EnvVar(..)));
+                spack_call
+                    .redirects_or_cmd_words
+                    .retain(|w| matches!(w, ast::RedirectOrCmdWord::CmdWord(_)));
+
+                let description = match normalize(&spack_call) {
+                    Ok(description) => description,
+                    Err(e) => {
+                        self.errors.push(e.context(&spack_call.into_string()));
+                        spack_call.into_string()
+                    }
+                };
+                debug!("Found spack call: {}", description);
+
+                // Identical calls share the same function, compiled once
+                let name = match self.names.get(&description) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let name = self.name(&spack_call, &description);
+                        self.spack_calls.push((name.clone(), spack_call));
+                        self.names.insert(description, name.clone());
+                        name
+                    }
+                };
+
+                spack
+                    .redirects_or_env_vars
+                    .retain(|v| matches!(v, ast::RedirectOrEnvVar::Redirect(_)));
+                spack
+                    .redirects_or_cmd_words
+                    .retain(|w| matches!(w, ast::RedirectOrCmdWord::Redirect(_)));
+                spack.redirects_or_cmd_words.insert(0, command_word!(name));
+            } else {
+                // Replacing the call would change the status it returns to
+                // conditions and pipelines, so spack is still needed to run it
+                warn!("Keeping spack call: {}", spack.into_string());
+            }
+        }
+    }
+
+    // Words are left untouched, as rewriting spack calls in command substitutions
+    // would change their output
+    fn visit_top_level_word(&mut self, _word: &mut ast::TopLevelWord<String>) {}
+}
+
+// Return true if a top-level command is a single simple command
+fn alone(cmd: &ast::TopLevelCommand<String>) -> bool {
+    let list = match &cmd.0 {
+        ast::Command::Job(list) | ast::Command::List(list) => list,
+    };
+
+    list.rest.is_empty()
+        && matches!(
+            list.first,
+            ast::ListableCommand::Single(ast::PipeableCommand::Simple(_))
+        )
+}
+
+fn filter_parser(
+    contents: &str,
+    rewriter: &mut SpackRewriter,
+) -> Result<Vec<Result<Line, Unparsed>>, Error> {
+    let mut lines = parse::lines(contents).collect::<Vec<_>>();
+
+    // The shebang is replaced by the one of the output script
+    if let Some(Ok(line)) = lines.first_mut() {
+        if line.leading.first().is_some_and(|c| c.starts_with("#!")) {
+            line.leading.remove(0);
+        }
+    }
+
+    for line in lines.iter_mut().filter_map(|line| line.as_mut().ok()) {
+        let ast = match line.command.as_mut() {
+            Some(ast) => ast,
+            None => continue,
+        };
+
+        // Top-level lines holding a lone spack setup source script, or a lone spack
+        // call we cannot compile, are removed altogether; their comments are kept
+        if alone(ast) {
+            if let Some(cmd) = ast.extract(".") {
+                if cmd.position(SPACK_SETUP)?.is_some() {
+                    rewriter.spack_source = cmd.clone();
+                    line.command = None;
+                    continue;
+                }
+            } else if ast
+                .extract("spack")
+                .is_some_and(|cmd| compilable(cmd).is_none())
+            {
+                line.command = None;
+                continue;
+            }
+        }
+
+        // Other occurrences are rewritten in place, as removing them could leave
+        // compound commands with an empty body, or drop the commands chained to them
+        rewriter.visit_top_level_command(ast);
+    }
+
+    Ok(lines)
+}
+
+// What a spack call amounts to once compiled
+struct Compiled {
+    // The modifications made to the environment
+    modifications: Vec<(String, Modification)>,
+    // The modifications undoing them
+    reverted: Vec<(String, Modification)>,
+    // The other commands output by spack
+    commands: Vec<ast::TopLevelCommand<String>>,
+}
+
+// Compile a spack load call natively from the install database
+fn compile_offline(
+    database: &Database,
+    call: &ast::DefaultSimpleCommand,
+) -> Result<Compiled, Error> {
+    let (verb, index) =
+        spack_verb(call).ok_or_else(|| Error::Unsupported(String::from("Not a spack call")))?;
+
+    if verb != ["load"] {
+        return Err(Error::Unsupported(format!(
+            "spack {} cannot be compiled offline",
+            verb.join(" ")
+        )));
+    }
+
+    let (flags, positionals) = split_arguments(call, index)
+        .ok_or_else(|| Error::Unsupported(String::from("Cannot resolve arguments offline")))?;
+
+    let first = flags.iter().any(|f| f == "--first");
+    let only = flags
+        .iter()
+        .find_map(|f| f.strip_prefix("--only="))
+        .unwrap_or("package,dependencies");
+
+    let words = positionals
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+
+    let mut roots = vec![];
+    for spec in spec::parse(&words)? {
+        match database.find(&spec).as_slice() {
+            [] => {
+                return Err(Error::Database(format!(
+                    "No installed package matches {}",
+                    spec
+                )))
+            }
+            [install] => roots.push(*install),
+            [install, ..] if first => roots.push(*install),
+            _ => {
+                return Err(Error::Database(format!(
+                    "{} matches multiple packages, use --first to load the first match",
+                    spec
+                )))
+            }
+        }
+    }
+
+    let installs = match only {
+        "package" => roots,
+        "dependencies" => database
+            .closure(&roots)
+            .into_iter()
+            .filter(|i| !roots.contains(i))
+            .collect(),
+        _ => database.closure(&roots),
+    };
+
+    let modifications = database::environment(&installs)
+        .into_iter()
+        .map(|(variable, entries)| match variable.as_str() {
+            set if environment::SETS.contains(&set) => (variable, Modification::Add(entries)),
+            _ => (variable, Modification::Prepend(entries)),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Compiled {
+        reverted: environment::revert(&Environment::new(), &modifications),
+        modifications,
+        commands: vec![],
+    })
+}
+
+// Return a spack call requesting shell output with the given flag
+fn with_flag(call: &ast::DefaultSimpleCommand, flag: &str) -> Option<ast::DefaultSimpleCommand> {
+    let (_, index) = spack_verb(call)?;
+    let mut call = call.clone();
+
+    // Some calls, such as env activate, may already request shell output
+    call.redirects_or_cmd_words.retain(
+        |word| !matches!(word, command_word!(w) if Shell::SPACK_FLAGS.contains(&w.as_str())),
+    );
+    call.redirects_or_cmd_words
+        .insert(index + 1, command_word!(flag.to_string()));
+
+    Some(call)
+}
+
+// Runs spack calls one after the other, each in the environment left by the
+// previous ones, as the generated script would
+struct Spack<'a> {
+    command: &'a [String],
+    environment: Environment,
+}
+
+impl<'a> Spack<'a> {
+    // Start from the current environment, without any spack environment or package
+    fn new(command: &'a [String]) -> Result<Self, Error> {
+        let mut spack = Spack {
+            command,
+            environment: env::vars().collect(),
+        };
+
+        // Deactivating fails when no environment is active
+        let _ = spack.run(&["env", "deactivate", "--sh"], &Environment::new());
+        spack.run(&["unload", "--all", "--sh"], &Environment::new())?;
+
+        Ok(spack)
+    }
+
+    // Run spack with the given arguments and variables set for it alone, and apply
+    // its shell output to the environment, returning the commands of the output
+    // that are not exports
+    fn run<S: AsRef<str>>(
+        &mut self,
+        arguments: &[S],
+        assignments: &Environment,
+    ) -> Result<Vec<ast::TopLevelCommand<String>>, Error> {
+        let (program, prefix) = self
+            .command
+            .split_first()
+            .ok_or_else(|| Error::Spack(String::from("No spack command given")))?;
+        let arguments = arguments.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+
+        debug!("Running {} {}", self.command.join(" "), arguments.join(" "));
+        let output = Command::new(program)
+            .args(prefix)
+            .args(&arguments)
+            .env_clear()
+            .envs(&self.environment)
+            .envs(assignments)
+            .output()
+            .map_err(|e| Error::Spack(format!("{}: {}", program, e)))?;
+
+        if !output.status.success() {
+            return Err(Error::Spack(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        self.apply(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    // Expand a word as the shell would, if it only depends on variables set in the
+    // environment and the home directory; outside of assignments, variables must
+    // be quoted, as their value would otherwise be split into several words
+    fn expand(&self, word: &ast::TopLevelWord<String>, assigned: bool) -> Option<String> {
+        let expand = |word: &ast::DefaultSimpleWord, quoted: bool| match word {
+            ast::SimpleWord::Param(ast::Parameter::Var(name)) if quoted || assigned => {
+                self.environment.get(name).cloned()
+            }
+            word => simple_literal(word, quoted),
+        };
+
+        let words = match &word.0 {
+            ast::ComplexWord::Single(w) => vec![w],
+            ast::ComplexWord::Concat(v) => v.iter().collect(),
+        };
+
+        words
+            .iter()
+            .enumerate()
+            .map(|(index, w)| match w {
+                ast::Word::Simple(ast::SimpleWord::Tilde) if index == 0 => {
+                    self.environment.get("HOME").cloned()
+                }
+                ast::Word::Simple(ast::SimpleWord::Tilde) => Some(String::from("~")),
+                ast::Word::Simple(w) => expand(w, false),
+                ast::Word::SingleQuoted(l) => Some(l.clone()),
+                ast::Word::DoubleQuoted(v) => v.iter().map(|w| expand(w, true)).collect(),
+            })
+            .collect()
+    }
+
+    // Apply the exports and unsets of spack's shell output to the environment,
+    // returning the other commands; exports depending on the environment are
+    // returned as well, to be evaluated when the script runs
+    fn apply(&mut self, output: &str) -> Result<Vec<ast::TopLevelCommand<String>>, Error> {
+        let mut commands = vec![];
+
+        for line in parse::lines(output) {
+            let mut command = match line?.command {
+                Some(command) => command,
+                None => continue,
+            };
+
+            if let Some(export) = command.extract("export") {
+                let assignments = export
+                    .redirects_or_cmd_words
+                    .iter()
+                    .skip(1)
+                    .map(|word| match word {
+                        ast::RedirectOrCmdWord::CmdWord(word) => literal(word),
+                        ast::RedirectOrCmdWord::Redirect(_) => None,
+                    })
+                    .collect::<Option<Vec<String>>>();
+
+                match assignments {
+                    Some(assignments) => {
+                        for (name, value) in assignments.iter().filter_map(|a| a.split_once('=')) {
+                            self.environment.insert(name.to_string(), value.to_string());
+                        }
+                    }
+                    None => commands.push(command),
+                }
+            } else if let Some(unset) = command.extract("unset") {
+                for word in unset.redirects_or_cmd_words.iter().skip(1) {
+                    if let command_word!(name) = word {
+                        self.environment.remove(name);
+                    }
+                }
+            } else {
+                commands.push(command);
+            }
+        }
+
+        Ok(commands)
+    }
+}
+
+// Compile a spack call by running spack
+fn compile_spack(spack: &mut Spack, call: &ast::DefaultSimpleCommand) -> Result<Compiled, Error> {
+    let call = with_flag(call, Shell::Sh.spack_flag())
+        .ok_or_else(|| Error::Unsupported(String::from("Not a spack call")))?;
+
+    let arguments = call.redirects_or_cmd_words[1..]
+        .iter()
+        .map(|word| match word {
+            ast::RedirectOrCmdWord::CmdWord(word) => spack.expand(word, false),
+            ast::RedirectOrCmdWord::Redirect(_) => None,
+        })
+        .collect::<Option<Vec<String>>>()
+        .ok_or_else(|| {
+            Error::Unsupported(String::from(
+                "Cannot resolve arguments before running spack",
+            ))
+        })?;
+
+    let assignments = call
+        .redirects_or_env_vars
+        .iter()
+        .filter_map(|assignment| match assignment {
+            ast::RedirectOrEnvVar::EnvVar(name, Some(value)) => {
+                Some(spack.expand(value, true).map(|value| (name.clone(), value)))
+            }
+            ast::RedirectOrEnvVar::EnvVar(name, None) => Some(Some((name.clone(), String::new()))),
+            ast::RedirectOrEnvVar::Redirect(_) => None,
+        })
+        .collect::<Option<Environment>>()
+        .ok_or_else(|| {
+            Error::Unsupported(String::from(
+                "Cannot resolve assignments before running spack",
+            ))
+        })?;
+
+    let before = spack.environment.clone();
+    let commands = spack.run(&arguments, &assignments)?;
+    let modifications = environment::diff(&before, &spack.environment);
+
+    Ok(Compiled {
+        reverted: environment::revert(&before, &modifications),
+        modifications,
+        commands,
+    })
+}
+
+// Shell code appending the output of a spack call to the final script, as the
+// body of a function named $HASH; variables are modified relative to their value
+// when the function runs
+fn staging(shell: Shell) -> String {
+    match shell {
+        Shell::Bash | Shell::Sh => format!(
+            "cat <<- EOF >> $BUFFER\n\t$HASH() {{\n\t# Output of '$@'\n\t$($@ | {})\n\t}}\n\nEOF",
+            RELATIVE_FUNC_NAME
+        ),
+        Shell::Fish => format!(
+            "cat <<- EOF >> $BUFFER\n\tfunction $HASH\n\t# Output of '$@'\n\t$($@ | {})\n\tend\n\nEOF",
+            RELATIVE_FUNC_NAME
+        ),
+        // Aliases hold a single line, quoted
+        Shell::Csh => format!(
+            concat!(
+                "echo \"# Output of '$@'\" >> $BUFFER\n",
+                "echo \"alias $HASH '$($@ | {} | sed -e \"s/'/'\\\\\\\\''/g\" | tr '\\n' ' ')'\" >> $BUFFER"
+            ),
+            RELATIVE_FUNC_NAME
+        ),
+    }
+}
+
+// Shell code rewriting the variables set by spack's output on its standard input
+// into modifications relative to their current value, in the syntax of the shell
+fn relative(shell: Shell) -> String {
+    let quote = |code: &str| Shell::Bash.quote(code);
+    let entries = String::from("@ENTRIES@");
+
+    format!(
+        "UNSPACK_PREPEND={} UNSPACK_APPEND={} UNSPACK_REMOVE={} UNSPACK_ENTRY={} UNSPACK_SETS={} awk {}",
+        quote(&shell.prepend("@NAME@", &entries)),
+        quote(&shell.append("@NAME@", &entries)),
+        quote(&shell.remove("@NAME@", &[String::from("@ENTRY@")])),
+        quote(&format!("-e {}", shell.quote("@ENTRY@"))),
+        quote(&environment::SETS.join(" ")),
+        quote(include_str!("relative.awk"))
+    )
+}
+
+// Shell code replacing this script with the final one; scripts for other shells
+// are not run, as they could not change the environment of the caller
+fn replace(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash | Shell::Sh => "source $BUFFER\nmv $BUFFER $THIS",
+        Shell::Csh | Shell::Fish => "mv $BUFFER $THIS",
+    }
+}
+
+// The text surrounding the commands of the generated script
+#[derive(Debug, Clone)]
+enum Frame {
+    // The template compiling the spack calls when run
+    Deferred {
+        spack_source: String,
+        directives: String,
+    },
+    // The functions replacing the spack calls
+    Compiled {
+        origin: &'static str,
+        functions: String,
+    },
+}
+
+// The result of unspacking a script, written out with write_to
+#[derive(Debug, Clone)]
+pub struct Unspacked {
+    // The spack calls found in the input, along with the name of the function
+    // replacing them
+    pub calls: Vec<(String, ast::DefaultSimpleCommand)>,
+    // Text that failed to parse and was copied to the script as is
+    pub unparsed: Vec<Unparsed>,
+    shell: Shell,
+    indent: Indent,
+    lines: Vec<Result<Line, Unparsed>>,
+    frame: Frame,
+}
+
+// The commands of the generated script, with comments and blank lines laid out
+// around them as in the input; each line is written as soon as it is formatted
+struct Commands<'a>(&'a Unspacked);
+
+impl fmt::Display for Commands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Unspacked {
+            shell,
+            indent,
+            lines,
+            ..
+        } = self.0;
+        let mut first = true;
+
+        for line in lines {
+            let text = match line {
+                Ok(line) => {
+                    let command = match &line.command {
+                        // Scripts kept in a POSIX shell are pretty-printed
+                        Some(ast) if shell.is_posix() => indent.format(ast),
+                        // Translation was checked when unspacking
+                        Some(ast) => ast.translate(*shell).map_err(|_| fmt::Error)?,
+                        None => String::new(),
+                    };
+                    line.surround(&command)
+                }
+                Err(unparsed) => vec![unparsed.text.clone()],
+            };
+
+            for text in text {
+                if !first {
+                    f.write_char('\n')?;
+                }
+                f.write_str(&text)?;
+                first = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Unspacked {
+    // Write the generated script
+    pub fn write_to<W: io::Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let shell = self.shell;
+
+        match &self.frame {
+            Frame::Deferred {
+                spack_source,
+                directives,
+            } => write!(
+                out,
+                include_str!("template.sh.fmt"),
+                spack_source,
+                shell.shebang(),
+                RELATIVE_FUNC_NAME,
+                relative(shell),
+                COMPILE_FUNC_NAME,
+                staging(shell),
+                shell.spack_flag(),
+                Commands(self),
+                directives,
+                replace(shell)
+            ),
+            Frame::Compiled { origin, functions } => write!(
+                out,
+                include_str!("unspacked.sh.fmt"),
+                shell.shebang(),
+                origin,
+                functions,
+                Commands(self)
+            ),
+        }
+    }
+
+    // Return the generated script
+    pub fn script(&self) -> String {
+        let mut script = vec![];
+        self.write_to(&mut script)
+            .expect("a Vec cannot fail to be written");
+        String::from_utf8(script).expect("the script is written from strings")
+    }
+}
+
+// How the spack calls are compiled
+#[derive(Debug, Default)]
+enum Compilation {
+    // By the generated script, when run where spack is available
+    #[default]
+    Deferred,
+    // From the install database of a spack installation
+    Offline(Database),
+    // By running a spack command
+    Spack(Vec<String>),
+}
+
+// Converts spacked scripts; the defaults generate a bash script to be run where
+// spack is available, which then overwrites itself with the unspacked version
+#[derive(Debug)]
+pub struct Unspacker {
+    shell: Shell,
+    indent: Indent,
+    pass_through: bool,
+    prefix: String,
+    compilation: Compilation,
+}
+
+impl Default for Unspacker {
+    fn default() -> Self {
+        Unspacker {
+            shell: Shell::default(),
+            indent: Indent::default(),
+            pass_through: false,
+            prefix: String::from(FUNCTION_PREFIX),
+            compilation: Compilation::default(),
+        }
+    }
+}
+
+impl Unspacker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The shell to generate the script for
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    // The indentation of compound commands, in POSIX shells
+    pub fn indent(mut self, indent: Indent) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    // Copy the text that fails to parse to the script instead of returning an error
+    pub fn pass_through(mut self, pass_through: bool) -> Self {
+        self.pass_through = pass_through;
+        self
+    }
+
+    // The prefix of the functions replacing the spack calls; it must start a valid
+    // function name, holding letters, digits and underscores but no leading digit
+    pub fn prefix(mut self, prefix: &str) -> Result<Self, Error> {
+        if prefix.starts_with(|c: char| c.is_ascii_digit())
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Error::Unsupported(format!(
+                "Invalid function prefix {}",
+                prefix
+            )));
+        }
+
+        self.prefix = String::from(prefix);
+        Ok(self)
+    }
+
+    // Compile the spack calls from the install database of a spack installation,
+    // producing the final script directly
+    pub fn offline(mut self, database: Database) -> Self {
+        self.compilation = Compilation::Offline(database);
+        self
+    }
+
+    // Compile the spack calls by running the given spack command, producing the
+    // final script directly
+    pub fn compile(mut self, command: &[&str]) -> Self {
+        self.compilation = Compilation::Spack(command.iter().map(|w| w.to_string()).collect());
+        self
+    }
+
+    pub fn unspack(&self, contents: &str) -> Result<Unspacked, Error> {
+        let shell = self.shell;
+
+        // This will hold the spack setup source call, the spack calls found in the
+        // script and the errors they raised
+        let mut rewriter = SpackRewriter::new(
+            &self.prefix,
+            !matches!(self.compilation, Compilation::Deferred),
+        );
+
+        // Parse our input!
+        let transformed = filter_parser(contents, &mut rewriter)?;
+        let SpackRewriter {
+            spack_calls,
+            spack_source,
+            errors,
+            ..
+        } = rewriter;
+
+        // Text that failed to parse is either reported or copied to the output as is
+        let unparsed = transformed
+            .iter()
+            .filter_map(|line| line.as_ref().err().cloned())
+            .collect::<Vec<Unparsed>>();
+
+        if let Some(unparsed) = unparsed.first().filter(|_| !self.pass_through) {
+            return Err(Error::Parse(unparsed.clone()));
+        }
+
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+
+        // Commands are translated when the script is written; constructs the
+        // target shell lacks are reported beforehand
+        if !shell.is_posix() {
+            for ast in transformed
+                .iter()
+                .filter_map(|line| line.as_ref().ok()?.command.as_ref())
+            {
+                ast.translate(shell)?;
+            }
+        }
+
+        let frame = match &self.compilation {
+            Compilation::Deferred => {
+                let directives = spack_calls
+                    .iter()
+                    .filter_map(|(name, call)| {
+                        // Assignments are set for the compilation, spack included
+                        let mut call = with_flag(call, shell.spack_flag())?;
+                        let assignments = call
+                            .redirects_or_env_vars
+                            .drain(..)
+                            .map(|assignment| format!("{} ", assignment.into_string()))
+                            .collect::<String>();
+
+                        Some(format!(
+                            "HASH={} {}{} {}",
+                            name,
+                            assignments,
+                            COMPILE_FUNC_NAME,
+                            call.into_string()
+                        ))
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+
+                Frame::Deferred {
+                    spack_source: spack_source.into_string(),
+                    directives,
+                }
+            }
+            Compilation::Offline(database) => {
+                self.compiled("the spack install database", &spack_calls, |call| {
+                    compile_offline(database, call)
+                })?
+            }
+            Compilation::Spack(command) => {
+                let mut spack = Spack::new(command)?;
+                self.compiled("the output of spack", &spack_calls, |call| {
+                    compile_spack(&mut spack, call)
+                })?
+            }
+        };
+
+        Ok(Unspacked {
+            calls: spack_calls,
+            unparsed,
+            shell,
+            indent: self.indent,
+            lines: transformed,
+            frame,
+        })
+    }
+
+    // Generate the functions replacing the spack calls, compiled in order with the
+    // given function
+    fn compiled<F>(
+        &self,
+        origin: &'static str,
+        spack_calls: &[(String, ast::DefaultSimpleCommand)],
+        mut compile: F,
+    ) -> Result<Frame, Error>
+    where
+        F: FnMut(&ast::DefaultSimpleCommand) -> Result<Compiled, Error>,
+    {
+        let shell = self.shell;
+
+        let modify = |modifications: &[(String, Modification)]| {
+            modifications
+                .iter()
+                .map(|(variable, modification)| shell.modify(variable, modification))
+                .collect::<Vec<String>>()
+        };
+
+        let mut functions = vec![];
+        for (name, call) in spack_calls {
+            let compiled = compile(call).map_err(|e| e.context(&call.into_string()))?;
+
+            let commands = compiled
+                .commands
+                .iter()
+                .map(|command| match command {
+                    command if shell.is_posix() => Ok(self.indent.format(command)),
+                    command => command.translate(shell),
+                })
+                .collect::<Result<Vec<String>, Error>>()?;
+
+            functions.push(shell.function(
+                name,
+                &format!("Compiled version of '{}'", call.into_string()),
+                &[modify(&compiled.modifications), commands].concat(),
+            ));
+
+            // Loads can be undone in the same shell, as with spack unload
+            if let Some(unload) = counterpart(&self.prefix, name) {
+                functions.push(shell.function(
+                    &unload,
+                    &format!("Undo '{}'", call.into_string()),
+                    &modify(&compiled.reverted),
+                ));
+            }
+        }
+
+        Ok(Frame::Compiled {
+            origin,
+            functions: functions.join("\n"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unspack() {
+        let unspacked = Unspacker::new()
+            .unspack("#!/bin/sh\n. /spack/share/spack/setup-env.sh\nspack find\nspack load zlib && echo a\n")
+            .unwrap();
+
+        assert_eq!(unspacked.calls.len(), 1);
+        let (name, call) = &unspacked.calls[0];
+        assert_eq!(name, "unspack_load_zlib");
+        assert_eq!(call.into_string(), "spack load zlib");
+
+        // The call site is replaced, and spack is only used to compile the script
+        assert!(unspacked
+            .script()
+            .contains(&format!("{} && echo a\n", name)));
+        assert!(unspacked
+            .script()
+            .contains("\n. /spack/share/spack/setup-env.sh\n"));
+        assert!(!unspacked.script().contains("spack find"));
+        assert!(!unspacked.script().contains("#!/bin/sh"));
+    }
+
+    // Stand-in for spack, recording the calls made in the environment
+    static SPACK: [&str; 4] = [
+        "sh",
+        "-c",
+        r#"case "$*" in
+            unload*) echo 'unset CALLS;' ;;
+            *fail) echo 'failed' >&2; exit 1 ;;
+            *config) echo "export CONFIG='$CONFIG';" ;;
+            env\ activate*) echo "export SPACK_ENV=/envs/$5; export PS1=\"[$5] \${PS1}\";"
+                echo "alias despacktivate='spack env deactivate';" ;;
+            *) echo "export CALLS='${CALLS:+$CALLS:}$*';"
+               echo "export PATH='/opt/$3/bin:$PATH';" ;;
+        esac"#,
+        "spack",
+    ];
+
+    #[test]
+    fn test_unspack_compile() {
+        let unspacked = Unspacker::new()
+            .compile(&SPACK)
+            .unspack("spack load a\nspack load ~/b\n")
+            .unwrap();
+
+        // Each call runs in the environment left by the previous ones
+        let home = env::var("HOME").unwrap_or_default();
+        assert!(unspacked.script().contains("export CALLS='load --sh a'\n"));
+        assert!(unspacked.script().contains(&format!(
+            "export CALLS='load --sh a:load --sh {}/b'\n",
+            home
+        )));
+        assert!(!unspacked.script().contains(COMPILE_FUNC_NAME));
+
+        // Paths are added to the value of the variables when the script runs
+        assert!(unspacked
+            .script()
+            .contains("export PATH=/opt/a/bin${PATH:+:$PATH}\n"));
+
+        // Each load has a counterpart undoing it
+        let unload = counterpart(FUNCTION_PREFIX, &unspacked.calls[1].0).unwrap();
+        let body = &unspacked.script()[unspacked.script().find(&unload).unwrap()..];
+        assert!(body.contains(&format!("grep -vxF -e '/opt/{}/b/bin'", home)));
+        assert!(body.contains("export CALLS='load --sh a'\n}"));
+
+        // Exports depending on the environment are evaluated when the script runs
+        let unspacked = Unspacker::new()
+            .compile(&SPACK)
+            .unspack("spack env activate -p e\n")
+            .unwrap();
+        assert!(unspacked.script().contains(&format!(
+            "{}() {{\n# Compiled version of 'spack env activate -p e'\nexport SPACK_ENV='/envs/e'\nexport PS1=\"[e] $PS1\"\nalias despacktivate='spack env deactivate'\n}}",
+            unspacked.calls[0].0
+        )));
+
+        // Aliases are defined with the syntax of csh
+        let unspacked = Unspacker::new()
+            .shell(Shell::Csh)
+            .compile(&SPACK)
+            .unspack("spack env activate e\n")
+            .unwrap();
+        assert!(unspacked
+            .script()
+            .contains("; alias despacktivate '\\''spack env deactivate'\\'''\n"));
+
+        // Arguments must not depend on anything but the environment spack runs in
+        for script in [
+            "spack load $(fail)\n",
+            "PKG=zlib\nspack load \"$PKG\"\n",
+            "spack load $HOME\n",
+        ] {
+            assert!(matches!(
+                Unspacker::new().compile(&SPACK).unspack(script),
+                Err(Error::Unsupported(_))
+            ));
+        }
+        assert!(Unspacker::new()
+            .compile(&SPACK)
+            .unspack("spack load \"$HOME\"\n")
+            .unwrap()
+            .script()
+            .contains(&format!("export PATH=/opt/{}/bin${{PATH:+:$PATH}}\n", home)));
+        assert_eq!(
+            Unspacker::new()
+                .compile(&SPACK)
+                .unspack("spack load fail\n")
+                .unwrap_err()
+                .to_string(),
+            "spack load fail: failed"
+        );
+    }
+
+    #[test]
+    fn test_compile_offline() {
+        let root = env::temp_dir().join(format!("unspack-offline-{}", std::process::id()));
+        for directory in ["zlib/lib", "zlib/share/man", "cmake/bin", "cmake/man"] {
+            std::fs::create_dir_all(root.join(directory)).unwrap();
+        }
+
+        let database = Database::parse(&format!(
+            r#"{{"database": {{"version": "7", "installs": {{
+                "aaaa": {{"spec": {{"name": "zlib", "version": "1.2.13", "hash": "aaaa"}},
+                         "path": "{root}/zlib", "installed": true, "explicit": false}},
+                "bbbb": {{"spec": {{"name": "cmake", "version": "3.24.2", "hash": "bbbb",
+                                  "dependencies": [{{"name": "zlib", "hash": "aaaa",
+                                                    "parameters": {{"deptypes": ["link"]}}}}]}},
+                         "path": "{root}/cmake", "installed": true, "explicit": true}}
+            }}}}}}"#,
+            root = root.display()
+        ))
+        .unwrap();
+        let call = |script: &str| Unspacker::new().unspack(script).unwrap().calls[0].1.clone();
+        let path = |entries: &[&str]| {
+            entries
+                .iter()
+                .map(|e| format!("{}/{}", root.display(), e))
+                .collect::<Vec<String>>()
+        };
+
+        let compiled = compile_offline(&database, &call("spack load cmake\n"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Dependencies are loaded first, so the package comes first in the paths
+        assert_eq!(
+            compiled.unwrap().modifications,
+            vec![
+                (
+                    "CMAKE_PREFIX_PATH".into(),
+                    Modification::Prepend(path(&["cmake", "zlib"]))
+                ),
+                (
+                    "LD_LIBRARY_PATH".into(),
+                    Modification::Prepend(path(&["zlib/lib"]))
+                ),
+                (
+                    "MANPATH".into(),
+                    Modification::Prepend(path(&["cmake/man", "zlib/share/man"]))
+                ),
+                ("PATH".into(), Modification::Prepend(path(&["cmake/bin"]))),
+                (
+                    "SPACK_LOADED_HASHES".into(),
+                    Modification::Add(vec!["bbbb".into(), "aaaa".into()])
+                ),
+            ]
+        );
+
+        assert!(matches!(
+            compile_offline(&database, &call("spack unload cmake\n")),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            compile_offline(&database, &call("spack load hdf5\n")),
+            Err(Error::Database(_))
+        ));
+    }
+
+    #[test]
+    fn test_unspack_duplicates() {
+        let unspacked = Unspacker::new()
+            .unspack(
+                "spack load --first --only package foo\n\
+                 spack  load --only=package 'foo'  --first\n\
+                 if true; then spack load --first --only package foo; fi\n\
+                 spack load --first foo\n",
+            )
+            .unwrap();
+
+        // Every call site remains, calling one of the two distinct functions
+        assert_eq!(unspacked.calls.len(), 2);
+        let (name, _) = &unspacked.calls[0];
+        assert_eq!(
+            unspacked
+                .script()
+                .matches(&format!("HASH={} ", name))
+                .count(),
+            1
+        );
+        assert_eq!(unspacked.script().lines().filter(|l| l == name).count(), 2);
+        assert!(unspacked
+            .script()
+            .contains(&format!("then\n    {}\nfi", name)));
+    }
+
+    #[test]
+    fn test_unspack_names() {
+        let unspacked = Unspacker::new()
+            .unspack(
+                "spack load trilinos@13.2 %gcc\n\
+                 spack env activate -d envs/build/\n\
+                 spack unload zlib\n\
+                 spack load zlib\n\
+                 spack load --first zlib\n",
+            )
+            .unwrap();
+        let names = unspacked
+            .calls
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(names[0], "unspack_load_trilinos_13_2");
+        assert_eq!(names[1], "unspack_env_activate_build");
+        assert_eq!(names[2], "unspack_unload_zlib");
+        assert_eq!(names[3], "unspack_load_zlib");
+
+        // A hash tells apart the calls whose names would be the same
+        assert!(names[4].starts_with("unspack_load_zlib_"));
+        assert_eq!(names[4].len(), "unspack_load_zlib_".len() + 8);
+
+        // Compiled loads get counterparts, whose names are taken as well
+        let unspacked = Unspacker::new()
+            .compile(&SPACK)
+            .unspack("spack unload zlib\nspack load zlib\n")
+            .unwrap();
+        assert_eq!(unspacked.calls[0].0, "unspack_unload_zlib");
+        assert!(unspacked.calls[1].0.starts_with("unspack_load_zlib_"));
+
+        let unspacked = Unspacker::new()
+            .prefix("my_")
+            .unwrap()
+            .unspack("spack load zlib\n")
+            .unwrap();
+        assert_eq!(unspacked.calls[0].0, "my_load_zlib");
+        assert_eq!(
+            counterpart("my_", "my_load_zlib").unwrap(),
+            "my_unload_zlib"
+        );
+
+        // Prefixes must not make the names invalid, or inject shell code
+        assert!(Unspacker::new().prefix("").is_ok());
+        assert!(Unspacker::new().prefix("1x").is_err());
+        assert!(Unspacker::new().prefix("x;rm -rf ~;").is_err());
+    }
+
+    #[test]
+    fn test_unspack_assignments() {
+        let script = "CONFIG=/x spack load config 2>/dev/null\nspack load config\n";
+
+        // Assignments apply to spack alone, and redirects to the call site
+        let unspacked = Unspacker::new().unspack(script).unwrap();
+        let (name, call) = &unspacked.calls[0];
+        assert_eq!(call.into_string(), "CONFIG=/x spack load config");
+        assert!(unspacked
+            .script()
+            .contains(&format!("\n{} 2> /dev/null\n", name)));
+        assert!(unspacked.script().contains(&format!(
+            "HASH={} CONFIG=/x {} spack load --sh config\n",
+            name, COMPILE_FUNC_NAME
+        )));
+        assert_ne!(unspacked.calls[1].0, *name);
+
+        let unspacked = Unspacker::new().compile(&SPACK).unspack(script).unwrap();
+        assert!(unspacked.script().contains("export CONFIG='/x'\n"));
+    }
+
+    // Run a script generated without compiling, with a stand-in for spack running
+    // the given commands for any load, and return the script it turns into
+    fn run_deferred(unspacker: Unspacker, script: &str, load: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("unspack-deferred-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let spack = dir.join("spack");
+        std::fs::write(
+            &spack,
+            format!(
+                "#!/bin/sh\ncase \"$1 $2\" in\nload\\ --*)\n{}\n;;\nesac\n",
+                load
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&spack, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = dir.join("script.sh");
+        std::fs::write(&path, unspacker.unspack(script).unwrap().script()).unwrap();
+
+        let status = Command::new("bash")
+            .arg(&path)
+            .env("PATH", format!("{}:/usr/bin:/bin", dir.display()))
+            .env("LIBRARY_PATH", "/usr/lib:/lib")
+            .env("SPACK_LOADED_HASHES", "aaaa:bbbb")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        let unspacked = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(status.success());
+        unspacked
+    }
+
+    #[test]
+    fn test_unspack_deferred() {
+        let load = "echo \"export PATH=/opt/a/bin:$PATH;\"\n\
+                    echo 'export LIBRARY_PATH=/usr/lib:/opt/a/lib;'\n\
+                    echo \"export MANPATH='/opt/a/man';\"\n\
+                    echo 'export CC=cc;'";
+
+        // Variables are modified relative to their value when the script runs
+        let unspacked = run_deferred(Unspacker::new(), "spack load a\n", load);
+        assert!(unspacked.contains(concat!(
+            "unspack_load_a() {\n",
+            "# Output of 'spack load --sh a'\n",
+            "export PATH=/opt/a/bin${PATH:+:$PATH};\n",
+            "export LIBRARY_PATH=\"$(printf '%s\\n' \"$LIBRARY_PATH\" | tr : '\\n' | grep -vxF -e '/lib' | paste -sd: -)\";\n",
+            "export LIBRARY_PATH=${LIBRARY_PATH:+$LIBRARY_PATH:}/opt/a/lib;\n",
+            "export MANPATH=/opt/a/man${MANPATH:+:$MANPATH};\n",
+            "export CC=cc;\n",
+            "}\n"
+        )));
+
+        let unspacked = run_deferred(
+            Unspacker::new().shell(Shell::Fish),
+            "spack load a\n",
+            "echo 'set -gx MANPATH /opt/a/man /opt/b/man;'",
+        );
+        assert!(unspacked.contains(
+            "test -n \"$MANPATH\"; and set -gx MANPATH \"/opt/a/man:/opt/b/man:$MANPATH\"; or set -gx MANPATH \"/opt/a/man:/opt/b/man\";\n"
+        ));
+
+        // Aliases hold the modifications on a single line
+        let unspacked = run_deferred(
+            Unspacker::new().shell(Shell::Csh),
+            "spack load a\n",
+            "echo 'setenv MANPATH /opt/a/man;'; echo 'setenv CC cc;'",
+        );
+        assert!(unspacked.contains(
+            "alias unspack_load_a 'setenv MANPATH \"/opt/a/man`printenv MANPATH | sed -e s/^/:/`\"; setenv CC cc; '\n"
+        ));
+
+        // Hashes are added unless present, whatever their order
+        let unspacked = run_deferred(
+            Unspacker::new(),
+            "spack load a\n",
+            "echo 'export SPACK_LOADED_HASHES=cccc:aaaa;'",
+        );
+        assert!(unspacked.contains(concat!(
+            "# Output of 'spack load --sh a'\n",
+            "export SPACK_LOADED_HASHES=\"$(printf '%s\\n' \"$SPACK_LOADED_HASHES\" | tr : '\\n' | grep -vxF -e 'bbbb' | paste -sd: -)\";\n",
+            "export SPACK_LOADED_HASHES=\"$(printf '%s\\n' \"$SPACK_LOADED_HASHES\" | tr : '\\n' | grep -vxF -e 'cccc' | paste -sd: -)\";\n",
+            "export SPACK_LOADED_HASHES=${SPACK_LOADED_HASHES:+$SPACK_LOADED_HASHES:}cccc;\n",
+            "}\n"
+        )));
+    }
+
+    #[test]
+    fn test_unspack_shells() {
+        // Only scripts for POSIX shells are run before replacing the template
+        let unspacked = Unspacker::new().unspack("spack load zlib\n").unwrap();
+        assert!(unspacked
+            .script()
+            .ends_with("source $BUFFER\nmv $BUFFER $THIS\n"));
+
+        for shell in [Shell::Csh, Shell::Fish] {
+            let unspacked = Unspacker::new()
+                .shell(shell)
+                .unspack("spack load zlib\n")
+                .unwrap();
+            assert!(unspacked.script().ends_with("\n\nmv $BUFFER $THIS\n"));
+        }
+    }
+
+    #[test]
+    fn test_unspack_kept() {
+        let script = "spack find\n\
+                      if spack find cmake; then echo a; fi\n\
+                      if ! spack find zlib | grep -q zlib; then exit 1; fi\n\
+                      spack find hdf5 && spack load hdf5\n\
+                      spack load --list | wc -l\n";
+        let unspacked = Unspacker::new().unspack(script).unwrap();
+        let name = &unspacked.calls[0].0;
+
+        // Only lone calls are removed, as the others decide what runs next
+        assert!(!unspacked.script().contains("spack find\n"));
+        assert!(unspacked
+            .script()
+            .contains("if spack find cmake; then\n    echo a\nfi\n"));
+        assert!(unspacked
+            .script()
+            .contains("if ! spack find zlib | grep -q zlib; then\n"));
+        assert!(unspacked
+            .script()
+            .contains(&format!("spack find hdf5 && {}\n", name)));
+        assert!(unspacked.script().contains("spack load --list | wc -l\n"));
+
+        // Commands chained to the spack setup script are kept
+        let script = ". /s/share/spack/setup-env.sh && spack load zlib && echo loaded\n";
+        let unspacked = Unspacker::new().unspack(script).unwrap();
+        let name = &unspacked.calls[0].0;
+
+        assert_eq!(name, "unspack_load_zlib");
+        assert!(unspacked
+            .script()
+            .contains(&format!(": && {} && echo loaded\n", name)));
+        assert!(unspacked
+            .script()
+            .contains("\n. /s/share/spack/setup-env.sh\n"));
+    }
+
+    #[test]
+    fn test_unspack_unparsed() {
+        let script = "echo a\necho ) b\n";
+
+        assert!(matches!(
+            Unspacker::new().unspack(script),
+            Err(Error::Parse(Unparsed { line: 2, .. }))
+        ));
+
+        let unspacked = Unspacker::new().pass_through(true).unspack(script).unwrap();
+        assert_eq!(unspacked.unparsed.len(), 1);
+        assert!(unspacked.script().contains("echo a\necho ) b\n"));
+    }
+}
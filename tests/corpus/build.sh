@@ -0,0 +1,28 @@
+#!/bin/bash
+set -eu
+
+log() {
+    echo "[$(date +%T)] $*" >&2
+}
+
+jobs=${JOBS:-$(nproc)}
+count=0
+while [ $count -lt 3 ]; do
+    count=$((count + 1))
+    log "attempt $count of 3"
+    make -j$jobs 'CFLAGS=-O2 -Wall' && break
+done
+
+until test -f build/done; do sleep 1; done
+
+for f in src/*.c include/?.h lib/[abc]*; do
+    echo "${f##*/}" "${f%.*}" "${#f}" "${f#src/}" "${f%%.c}"
+done
+
+{ echo start; ./run.sh; } >> run.log 2>&1 &
+( cd build && make install ) || log "install failed with $?"
+wait $!
+echo \$HOME is "$HOME" 'and $PATH is' "\"$PATH\"" ~ ~/bin a:~
+echo "${1}0" ${10} "$@" "$#" "$-" $$ $0 "${VAR?unset}" ${VAR+set} ${VAR:+set} ${VAR=default}
+echo $(( (1 + 2) * 3 )) $(( count > 2 ? count << 1 : -count )) $(( x = 2 ** 3, x % 5 ))
+exec 3<> /dev/tcp/localhost/80 4<&- 5>| out 1>&2
@@ -0,0 +1,25 @@
+#!/bin/bash
+# Write a module file and a job script from heredocs
+cat > modulefile <<EOF
+#%Module
+prepend-path PATH $PREFIX/bin
+setenv CC \$CC_$(hostname)
+EOF
+
+cat <<'EOF' | sbatch --parsable && echo submitted
+#!/bin/bash
+#SBATCH -N 1
+srun $HOME/bin/app "$@" `date`
+EOF
+
+if [ -n "$DEBUG" ]; then
+	cat 3<<-END >&3
+	EOF
+	debugging ${DEBUG}
+	END
+fi
+
+message=$(cat <<EOF
+done on $(date)
+EOF
+)
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Pick the first available compiler module
+for compiler in gcc/12 gcc/11 intel/2021; do
+    if module avail "$compiler" 2>&1 | grep -q "${compiler%%/*}"; then
+        module load $compiler && break
+    fi
+done
+
+case "$(hostname -s)" in
+    login*|front[0-9]) PARTITION=debug ;;
+    node-*) PARTITION=compute ;;
+    *) PARTITION= ;;
+esac
+
+: ${SCRATCH:=/tmp/$USER}
+mkdir -p "$SCRATCH/${PARTITION:-default}" || exit $?
@@ -0,0 +1,16 @@
+#!/bin/bash
+# Set up a build environment with spack
+. /spack/share/spack/setup-env.sh
+
+export SPACK_ROOT=/spack
+spack env activate -d ~/envs/build
+spack load --first cmake@3.20: %gcc@11
+spack load hdf5+mpi ^openmpi@4.1 cflags="-O3 -g"
+
+if ! spack find --format '{name}' zlib | grep -q zlib; then
+    echo "zlib is missing" >&2
+    exit 1
+fi
+
+CC=$(which gcc) CXX=$(which g++) cmake -S . -B build > build.log 2>&1
+spack env deactivate
@@ -0,0 +1,428 @@
+extern crate unspacklib;
+
+use conch_parser::ast;
+use conch_parser::lexer::Lexer;
+use conch_parser::parse::DefaultParser;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use unspacklib::serializable::{Indent, Serializable};
+
+fn parse(script: &str) -> Result<Vec<ast::TopLevelCommand<String>>, String> {
+    DefaultParser::new(Lexer::new(script.chars()))
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}\n--- while parsing ---\n{}", e, script))
+}
+
+// Check that serializing the parsed script, plainly or pretty-printed, yields the
+// same tree once parsed again
+fn assert_roundtrip(script: &str) {
+    for cmd in parse(script).unwrap() {
+        for serialized in [
+            cmd.into_string(),
+            Indent::default().format(&cmd),
+            Indent::Tabs.format(&cmd),
+        ] {
+            let reparsed = parse(&serialized).unwrap();
+
+            assert_eq!(
+                reparsed,
+                vec![cmd.clone()],
+                "\n--- serialized ---\n{}\n--- script ---\n{}",
+                serialized,
+                script
+            );
+        }
+    }
+}
+
+#[test]
+fn test_roundtrip_corpus() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut scripts = fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    scripts.sort();
+
+    assert!(!scripts.is_empty());
+    for script in scripts {
+        assert_roundtrip(&fs::read_to_string(script).unwrap());
+    }
+}
+
+#[test]
+fn test_roundtrip_control_characters() {
+    // Control characters are plain text, whatever the serializer does with lines
+    for script in [
+        "echo 'a\u{1}b' \"c\u{0}d\"\n",
+        "x=$(echo 'a\u{1}b'; cat <<EOF\nc\u{0}d\nEOF\n)\n",
+        "cat <<EOF | grep \"\u{1}\"\na\u{1}b\u{0}\nEOF\n",
+    ] {
+        assert_roundtrip(script);
+    }
+}
+
+// Pseudo-random generator of syntax trees; a fixed seed keeps failures
+// reproducible
+struct Generator(u64);
+
+const NAMES: [&str; 4] = ["x", "PATH", "spack_root", "_v1"];
+const COMMANDS: [&str; 4] = ["echo", "spack", "true", "module"];
+const LITERALS: [&str; 6] = ["load", "zlib@1.2", "--first", "/tmp/a.b", "-v", "x,y"];
+const ESCAPED: [&str; 8] = [" ", "$", "'", "\"", "*", "&", ";", "|"];
+const QUOTED_ESCAPED: [&str; 4] = ["$", "\"", "\\", "`"];
+const QUOTED_LITERALS: [&str; 4] = ["a b", "it's", "x=1;", "(~)"];
+const HEREDOC_LITERALS: [&str; 5] = ["EOF\n", "EOF_1\n", "'a' \"b\"\n", "\tc\n", "d "];
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick(&mut self, items: &[&str]) -> String {
+        String::from(items[self.below(items.len())])
+    }
+
+    fn some<T>(&mut self, min: usize, max: usize, mut f: impl FnMut(&mut Self) -> T) -> Vec<T> {
+        let count = min + self.below(max - min + 1);
+        (0..count).map(|_| f(self)).collect()
+    }
+
+    fn parameter(&mut self) -> ast::DefaultParameter {
+        match self.below(9) {
+            0 => ast::Parameter::At,
+            1 => ast::Parameter::Star,
+            2 => ast::Parameter::Pound,
+            3 => ast::Parameter::Question,
+            4 => ast::Parameter::Dash,
+            5 => ast::Parameter::Dollar,
+            6 => ast::Parameter::Bang,
+            7 => ast::Parameter::Positional(self.below(12) as u32),
+            _ => ast::Parameter::Var(self.pick(&NAMES)),
+        }
+    }
+
+    fn arithmetic(&mut self, depth: usize) -> ast::DefaultArithmetic {
+        if depth == 0 {
+            return match self.below(2) {
+                0 => ast::Arithmetic::Var(self.pick(&NAMES)),
+                _ => ast::Arithmetic::Literal(self.below(100) as isize),
+            };
+        }
+
+        let operand = |g: &mut Self| Box::new(g.arithmetic(depth - 1));
+        match self.below(31) {
+            0 => ast::Arithmetic::Var(self.pick(&NAMES)),
+            1 => ast::Arithmetic::Literal(self.below(100) as isize),
+            2 => ast::Arithmetic::Pow(operand(self), operand(self)),
+            3 => ast::Arithmetic::PostIncr(self.pick(&NAMES)),
+            4 => ast::Arithmetic::PostDecr(self.pick(&NAMES)),
+            5 => ast::Arithmetic::PreIncr(self.pick(&NAMES)),
+            6 => ast::Arithmetic::PreDecr(self.pick(&NAMES)),
+            7 => ast::Arithmetic::UnaryPlus(operand(self)),
+            8 => ast::Arithmetic::UnaryMinus(operand(self)),
+            9 => ast::Arithmetic::LogicalNot(operand(self)),
+            10 => ast::Arithmetic::BitwiseNot(operand(self)),
+            11 => ast::Arithmetic::Mult(operand(self), operand(self)),
+            12 => ast::Arithmetic::Div(operand(self), operand(self)),
+            13 => ast::Arithmetic::Modulo(operand(self), operand(self)),
+            14 => ast::Arithmetic::Add(operand(self), operand(self)),
+            15 => ast::Arithmetic::Sub(operand(self), operand(self)),
+            16 => ast::Arithmetic::ShiftLeft(operand(self), operand(self)),
+            17 => ast::Arithmetic::ShiftRight(operand(self), operand(self)),
+            18 => ast::Arithmetic::Less(operand(self), operand(self)),
+            19 => ast::Arithmetic::LessEq(operand(self), operand(self)),
+            20 => ast::Arithmetic::Great(operand(self), operand(self)),
+            21 => ast::Arithmetic::GreatEq(operand(self), operand(self)),
+            22 => ast::Arithmetic::Eq(operand(self), operand(self)),
+            23 => ast::Arithmetic::NotEq(operand(self), operand(self)),
+            24 => ast::Arithmetic::BitwiseAnd(operand(self), operand(self)),
+            25 => ast::Arithmetic::BitwiseXor(operand(self), operand(self)),
+            26 => ast::Arithmetic::BitwiseOr(operand(self), operand(self)),
+            27 => ast::Arithmetic::LogicalAnd(operand(self), operand(self)),
+            28 => ast::Arithmetic::LogicalOr(operand(self), operand(self)),
+            29 => ast::Arithmetic::Ternary(operand(self), operand(self), operand(self)),
+            _ => match self.below(2) {
+                0 => ast::Arithmetic::Assign(self.pick(&NAMES), operand(self)),
+                _ => ast::Arithmetic::Sequence(self.some(2, 3, |g| g.arithmetic(depth - 1))),
+            },
+        }
+    }
+
+    fn substitution(&mut self, depth: usize) -> ast::DefaultParameterSubstitution {
+        let colon = self.below(2) == 0;
+        let parameter = self.parameter();
+        let word = match self.below(3) {
+            0 => None,
+            _ => Some(self.word(depth.saturating_sub(1))),
+        };
+
+        match self.below(12) {
+            0 => ast::ParameterSubstitution::Command(self.lines(depth.saturating_sub(1))),
+            1 => ast::ParameterSubstitution::Len(parameter),
+            2 => ast::ParameterSubstitution::Arith(match self.below(4) {
+                0 => None,
+                _ => Some(self.arithmetic(depth)),
+            }),
+            3 => ast::ParameterSubstitution::Default(colon, parameter, word),
+            4 => ast::ParameterSubstitution::Assign(colon, parameter, word),
+            5 => ast::ParameterSubstitution::Error(colon, parameter, word),
+            6 => ast::ParameterSubstitution::Alternative(colon, parameter, word),
+            7 => ast::ParameterSubstitution::RemoveSmallestSuffix(parameter, word),
+            8 => ast::ParameterSubstitution::RemoveLargestSuffix(parameter, word),
+            9 => ast::ParameterSubstitution::RemoveSmallestPrefix(parameter, word),
+            10 => ast::ParameterSubstitution::RemoveLargestPrefix(parameter, word),
+            _ => ast::ParameterSubstitution::Command(vec![]),
+        }
+    }
+
+    fn simple_word(&mut self, depth: usize, quoted: bool) -> ast::DefaultSimpleWord {
+        match (quoted, self.below(12)) {
+            (_, 0) => ast::SimpleWord::Param(self.parameter()),
+            (_, 1) if depth > 0 => ast::SimpleWord::Subst(Box::new(self.substitution(depth))),
+            (true, 2) => ast::SimpleWord::Escaped(self.pick(&QUOTED_ESCAPED)),
+            (true, 3) => ast::SimpleWord::Literal(self.pick(&QUOTED_LITERALS)),
+            (true, _) => ast::SimpleWord::Literal(self.pick(&LITERALS)),
+            (false, 2) => ast::SimpleWord::Escaped(self.pick(&ESCAPED)),
+            (false, 3) => ast::SimpleWord::Star,
+            (false, 4) => ast::SimpleWord::Question,
+            (false, 5) => ast::SimpleWord::SquareOpen,
+            (false, 6) => ast::SimpleWord::SquareClose,
+            (false, 7) => ast::SimpleWord::Tilde,
+            (false, 8) => ast::SimpleWord::Colon,
+            (false, _) => ast::SimpleWord::Literal(self.pick(&LITERALS)),
+        }
+    }
+
+    fn word(&mut self, depth: usize) -> ast::TopLevelWord<String> {
+        let mut part = |g: &mut Self| match g.below(5) {
+            0 => ast::Word::SingleQuoted(g.pick(&QUOTED_LITERALS).replace('\'', "")),
+            1 => ast::Word::DoubleQuoted(g.some(0, 3, |g| g.simple_word(depth, true))),
+            _ => ast::Word::Simple(g.simple_word(depth, false)),
+        };
+
+        ast::TopLevelWord(match self.below(3) {
+            0 => ast::ComplexWord::Concat(self.some(2, 3, &mut part)),
+            _ => ast::ComplexWord::Single(part(self)),
+        })
+    }
+
+    // Bodies of heredocs, where quotes are literal
+    fn heredoc(&mut self, depth: usize) -> ast::TopLevelWord<String> {
+        let mut body = self.some(1, 4, |g| {
+            ast::Word::Simple(match g.below(5) {
+                0 => ast::SimpleWord::Param(g.parameter()),
+                1 if depth > 0 => ast::SimpleWord::Subst(Box::new(g.substitution(depth))),
+                2 => ast::SimpleWord::Escaped(g.pick(&QUOTED_ESCAPED)),
+                _ => ast::SimpleWord::Literal(g.pick(&HEREDOC_LITERALS)),
+            })
+        });
+        body.push(ast::Word::Simple(ast::SimpleWord::Literal(String::from(
+            "\n",
+        ))));
+
+        ast::TopLevelWord(ast::ComplexWord::Concat(body))
+    }
+
+    fn redirect(&mut self, depth: usize) -> ast::DefaultRedirect {
+        let fd = match self.below(2) {
+            0 => None,
+            _ => Some(self.below(10) as u16),
+        };
+        let dup = ast::TopLevelWord(ast::ComplexWord::Single(ast::Word::Simple(
+            ast::SimpleWord::Literal(self.pick(&["0", "1", "2", "-"])),
+        )));
+
+        match self.below(7) {
+            0 => ast::Redirect::Read(fd, self.word(depth)),
+            1 => ast::Redirect::Write(fd, self.word(depth)),
+            2 => ast::Redirect::ReadWrite(fd, self.word(depth)),
+            3 => ast::Redirect::Append(fd, self.word(depth)),
+            4 => ast::Redirect::Clobber(fd, self.word(depth)),
+            5 => ast::Redirect::DupRead(fd, dup),
+            _ => ast::Redirect::DupWrite(fd, dup),
+        }
+    }
+
+    fn simple_command(&mut self, depth: usize) -> ast::DefaultSimpleCommand {
+        let mut redirects_or_env_vars = self.some(0, 2, |g| match g.below(3) {
+            0 => ast::RedirectOrEnvVar::Redirect(g.redirect(depth)),
+            1 => ast::RedirectOrEnvVar::EnvVar(g.pick(&NAMES), None),
+            _ => ast::RedirectOrEnvVar::EnvVar(g.pick(&NAMES), Some(g.word(depth))),
+        });
+
+        let mut redirects_or_cmd_words = vec![];
+        if self.below(4) != 0 || redirects_or_env_vars.is_empty() {
+            let name = ast::TopLevelWord(ast::ComplexWord::Single(ast::Word::Simple(
+                ast::SimpleWord::Literal(self.pick(&COMMANDS)),
+            )));
+            redirects_or_cmd_words.push(ast::RedirectOrCmdWord::CmdWord(name));
+            redirects_or_cmd_words.append(&mut self.some(0, 3, |g| match g.below(4) {
+                0 => ast::RedirectOrCmdWord::Redirect(g.redirect(depth)),
+                _ => ast::RedirectOrCmdWord::CmdWord(g.word(depth)),
+            }));
+        } else if redirects_or_env_vars.len() == 1 {
+            // A lone redirection is a valid command
+            redirects_or_env_vars.push(ast::RedirectOrEnvVar::Redirect(self.redirect(depth)));
+        }
+
+        ast::SimpleCommand {
+            redirects_or_env_vars,
+            redirects_or_cmd_words,
+        }
+    }
+
+    fn guard_body_pair(
+        &mut self,
+        depth: usize,
+    ) -> ast::GuardBodyPair<ast::TopLevelCommand<String>> {
+        ast::GuardBodyPair {
+            guard: self.commands(depth),
+            body: self.lines(depth),
+        }
+    }
+
+    fn compound_command(&mut self, depth: usize) -> ast::DefaultCompoundCommand {
+        let kind = match self.below(7) {
+            0 => ast::CompoundCommandKind::Brace(self.commands(depth)),
+            1 => ast::CompoundCommandKind::Subshell(self.commands(depth)),
+            2 => ast::CompoundCommandKind::While(self.guard_body_pair(depth)),
+            3 => ast::CompoundCommandKind::Until(self.guard_body_pair(depth)),
+            4 => ast::CompoundCommandKind::If {
+                conditionals: self.some(1, 3, |g| g.guard_body_pair(depth)),
+                else_branch: match self.below(2) {
+                    0 => None,
+                    _ => Some(self.lines(depth)),
+                },
+            },
+            5 => ast::CompoundCommandKind::For {
+                var: self.pick(&NAMES),
+                words: match self.below(3) {
+                    0 => None,
+                    _ => Some(self.some(0, 3, |g| g.word(depth))),
+                },
+                body: self.lines(depth),
+            },
+            _ => ast::CompoundCommandKind::Case {
+                word: self.word(depth),
+                arms: self.some(0, 3, |g| ast::PatternBodyPair {
+                    patterns: g.some(1, 2, |g| g.word(depth)),
+                    body: g.some(0, 2, |g| g.line(depth)),
+                }),
+            },
+        };
+
+        ast::CompoundCommand {
+            kind,
+            io: self.some(0, 1, |g| g.redirect(depth)),
+        }
+    }
+
+    fn pipeable_command(&mut self, depth: usize) -> ast::DefaultPipeableCommand {
+        match (depth, self.below(4)) {
+            (0, _) | (_, 0) | (_, 1) => {
+                ast::PipeableCommand::Simple(Box::new(self.simple_command(depth)))
+            }
+            (_, 2) => ast::PipeableCommand::Compound(Box::new(self.compound_command(depth - 1))),
+            _ => ast::PipeableCommand::FunctionDef(
+                self.pick(&NAMES),
+                Rc::new(self.compound_command(depth - 1)),
+            ),
+        }
+    }
+
+    fn listable_command(&mut self, depth: usize) -> ast::DefaultListableCommand {
+        match self.below(3) {
+            0 => ast::ListableCommand::Pipe(
+                self.below(2) == 0,
+                self.some(1, 3, |g| g.pipeable_command(depth)),
+            ),
+            _ => ast::ListableCommand::Single(self.pipeable_command(depth)),
+        }
+    }
+
+    fn command(&mut self, depth: usize) -> ast::TopLevelCommand<String> {
+        let list = ast::AndOrList {
+            first: self.listable_command(depth),
+            rest: self.some(0, 2, |g| match g.below(2) {
+                0 => ast::AndOr::And(g.listable_command(depth)),
+                _ => ast::AndOr::Or(g.listable_command(depth)),
+            }),
+        };
+
+        ast::TopLevelCommand(match self.below(5) {
+            0 => ast::Command::Job(list),
+            _ => ast::Command::List(list),
+        })
+    }
+
+    fn commands(&mut self, depth: usize) -> Vec<ast::TopLevelCommand<String>> {
+        self.some(1, 2, |g| g.command(depth))
+    }
+
+    // A command ending a line, which may end with a heredoc. The parser does
+    // not read heredoc bodies pending when a compound command spans the end
+    // of the line, so heredocs are only attached where nothing else follows
+    fn line(&mut self, depth: usize) -> ast::TopLevelCommand<String> {
+        let mut cmd = self.command(depth);
+
+        let (ast::Command::Job(list) | ast::Command::List(list)) = &mut cmd.0;
+        let last = match list.rest.last_mut() {
+            Some(ast::AndOr::And(last) | ast::AndOr::Or(last)) => last,
+            None => &mut list.first,
+        };
+        let last = match last {
+            ast::ListableCommand::Pipe(_, cmds) => cmds.last_mut().unwrap(),
+            ast::ListableCommand::Single(cmd) => cmd,
+        };
+
+        if let ast::PipeableCommand::Simple(simple) = last {
+            if self.below(3) == 0 {
+                let fd = self.some(0, 1, |g| g.below(10) as u16).pop();
+                let heredoc = ast::Redirect::Heredoc(fd, self.heredoc(depth));
+                if simple.redirects_or_cmd_words.is_empty() {
+                    simple
+                        .redirects_or_env_vars
+                        .push(ast::RedirectOrEnvVar::Redirect(heredoc));
+                } else {
+                    simple
+                        .redirects_or_cmd_words
+                        .push(ast::RedirectOrCmdWord::Redirect(heredoc));
+                }
+            }
+        }
+
+        cmd
+    }
+
+    fn lines(&mut self, depth: usize) -> Vec<ast::TopLevelCommand<String>> {
+        self.some(1, 2, |g| g.line(depth))
+    }
+}
+
+#[test]
+fn test_roundtrip_generated() {
+    for seed in 1..=500 {
+        let mut generator = Generator(seed);
+
+        for cmd in generator.lines(2) {
+            let script = cmd.into_string();
+
+            if let Err(e) = parse(&script) {
+                panic!("seed {}: serialized tree does not parse: {}", seed, e);
+            }
+
+            assert_roundtrip(&script);
+        }
+    }
+}